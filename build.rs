@@ -1,4 +1,11 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/validator.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    // The descriptor set is served by gRPC reflection.
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("validator_descriptor.bin"))
+        .compile(&["proto/validator.proto"], &["proto"])?;
     Ok(())
 }
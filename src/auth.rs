@@ -0,0 +1,113 @@
+use subtle::ConstantTimeEq;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// What a gRPC caller is allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Query the state.
+    Read,
+    /// Also connect and disconnect blocks.
+    Write,
+}
+
+/// Checks the bearer token of every request and attaches the caller's `Scope` to it.
+///
+/// Without any token everything is open to everyone. Otherwise the write scope takes the write
+/// token, which grants the read scope too, and the read scope is open to everyone unless it has a
+/// token of its own.
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    read_token: Option<String>,
+    write_token: Option<String>,
+}
+
+impl AuthInterceptor {
+    pub fn new(read_token: Option<String>, write_token: Option<String>) -> Self {
+        Self {
+            read_token,
+            write_token,
+        }
+    }
+
+    fn scope(&self, token: Option<&str>) -> Result<Scope, &'static str> {
+        if self.read_token.is_none() && self.write_token.is_none() {
+            return Ok(Scope::Write);
+        }
+        match token {
+            Some(token) if matches(token, &self.write_token) => Ok(Scope::Write),
+            Some(token) if matches(token, &self.read_token) => Ok(Scope::Read),
+            Some(_) => Err("invalid bearer token"),
+            None if self.read_token.is_none() => Ok(Scope::Read),
+            None => Err("missing bearer token"),
+        }
+    }
+}
+
+/// Whether `token` is `expected`, in constant time so that it can't be guessed byte by byte.
+fn matches(token: &str, expected: &Option<String>) -> bool {
+    expected
+        .as_ref()
+        .is_some_and(|expected| bool::from(token.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = match request.metadata().get("authorization") {
+            Some(authorization) => {
+                let token = authorization
+                    .to_str()
+                    .ok()
+                    .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                    .ok_or_else(|| Status::unauthenticated("invalid authorization header"))?;
+                Some(token.to_owned())
+            }
+            None => None,
+        };
+        let scope = self
+            .scope(token.as_deref())
+            .map_err(Status::unauthenticated)?;
+        request.extensions_mut().insert(scope);
+        Ok(request)
+    }
+}
+
+/// Whether the caller of `request` may connect and disconnect blocks.
+pub fn has_write_scope<T>(request: &Request<T>) -> bool {
+    request.extensions().get::<Scope>() == Some(&Scope::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interceptor(read_token: Option<&str>, write_token: Option<&str>) -> AuthInterceptor {
+        AuthInterceptor::new(read_token.map(String::from), write_token.map(String::from))
+    }
+
+    #[test]
+    fn everything_is_open_without_tokens() {
+        assert_eq!(interceptor(None, None).scope(None), Ok(Scope::Write));
+    }
+
+    #[test]
+    fn read_tokens_never_grant_write() {
+        let auth = interceptor(Some("read"), None);
+        assert_eq!(auth.scope(Some("read")), Ok(Scope::Read));
+        assert!(auth.scope(None).is_err());
+        assert!(auth.scope(Some("other")).is_err());
+    }
+
+    #[test]
+    fn write_tokens_grant_read_too() {
+        let auth = interceptor(Some("read"), Some("write"));
+        assert_eq!(auth.scope(Some("write")), Ok(Scope::Write));
+        assert_eq!(auth.scope(Some("read")), Ok(Scope::Read));
+        assert!(auth.scope(None).is_err());
+
+        let auth = interceptor(None, Some("write"));
+        assert_eq!(auth.scope(Some("write")), Ok(Scope::Write));
+        assert_eq!(auth.scope(None), Ok(Scope::Read));
+        assert!(auth.scope(Some("read")).is_err());
+    }
+}
@@ -1,51 +1,618 @@
+use crate::metrics::Metrics;
 use crate::types::*;
 use bip300_messages::{
-    parse_coinbase_script, sha256d, CoinbaseMessage, M4AckBundles, ABSTAIN_ONE_BYTE,
-    ABSTAIN_TWO_BYTES, ALARM_ONE_BYTE, ALARM_TWO_BYTES, OP_DRIVECHAIN,
+    parse_coinbase_script, sha256d, CoinbaseMessage, M4AckBundles, OP_DRIVECHAIN,
 };
+use bitcoin::hashes::Hash;
 use bitcoin::opcodes::all::OP_PUSHBYTES_1;
 use bitcoin::opcodes::OP_TRUE;
-use bitcoin::{Block, OutPoint, Transaction};
-use miette::{miette, IntoDiagnostic, Result};
-use redb::{Database, ReadableTable, TableDefinition};
+use bitcoin::{Block, BlockHash, Network, OutPoint, Script, ScriptBuf, Transaction};
+use miette::{miette, IntoDiagnostic, Report, Result};
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::broadcast;
+use tracing::{debug, debug_span, info, info_span};
+
+mod bundles;
+mod commitment;
+mod history;
+mod migrations;
+mod snapshot;
+mod validation;
+
+use history::{
+    BUNDLE_HISTORY, CTIP_HISTORY, HISTORY_PRUNED_HEIGHT, PREVIOUS_VOTES_HISTORY, SIDECHAIN_HISTORY,
+    SIDECHAIN_PROPOSAL_HISTORY,
+};
 
 const DATA_HASH_TO_SIDECHAIN_PROPOSAL: TableDefinition<&Hash256, SidechainProposal> =
     TableDefinition::new("data_hash_to_sidechain_proposal");
 
-const SIDECHAIN_NUMBER_TO_BUNDLES: TableDefinition<u8, Vec<Bundle>> =
-    TableDefinition::new("sidechain_number_to_bundles");
+const BUNDLE_INDEX_TO_BUNDLE: TableDefinition<(u8, u32), Bundle> =
+    TableDefinition::new("bundle_index_to_bundle");
+
+const BUNDLE_TXID_TO_BUNDLE_INDEX: TableDefinition<(u8, &Hash256), u32> =
+    TableDefinition::new("bundle_txid_to_bundle_index");
 
 const SIDECHAIN_NUMBER_TO_SIDECHAIN: TableDefinition<u8, Sidechain> =
     TableDefinition::new("sidechain_number_to_sidechain");
 
-const PREVIOUS_VOTES: TableDefinition<(), Vec<&Hash256>> =
-    TableDefinition::new("previous_vote_vector");
-
-const LEADING_BY_50: TableDefinition<(), Vec<&Hash256>> = TableDefinition::new("leading_by_50");
+/// Votes of the last M4, indexed by sidechain number, for M4s that repeat them. Trailing
+/// abstentions are left out, and there is no entry if every sidechain abstained.
+const PREVIOUS_VOTES: TableDefinition<(), Vec<Vote>> = TableDefinition::new("previous_votes");
 
 const SIDECHAIN_NUMBER_TO_CTIP: TableDefinition<u8, Ctip> =
     TableDefinition::new("sidechain_number_to_ctip");
 
+const BLOCK_HEIGHT_TO_EVENTS: TableDefinition<u32, Vec<Event>> =
+    TableDefinition::new("block_height_to_events");
+
+const BLOCK_HEIGHT_TO_BLOCK_INFO: TableDefinition<u32, BlockInfo> =
+    TableDefinition::new("block_height_to_block_info");
+
+/// Consensus parameters the database was built with.
+const CONSENSUS_PARAMS: TableDefinition<(), ConsensusParams> =
+    TableDefinition::new("consensus_params");
+
+pub const SCHEMA_VERSION: u32 = 3;
+
+const EVENTS_CHANNEL_CAPACITY: usize = 4096;
+
+/// Outcome of connecting a batch of blocks.
+pub struct ConnectedBlocks {
+    pub count: usize,
+    /// Height of the last block connected, unset if none was.
+    pub last_height: Option<u32>,
+    /// Height of the block that was rejected, and why.
+    pub failure: Option<(u32, Report)>,
+}
+
 pub struct Bip300 {
     db: Database,
+    network: Network,
+    consensus_params: ConsensusParams,
+    start_block: StartBlock,
+    /// Number of blocks of history kept for queries and disconnects, all of it if unset.
+    history_depth: Option<u32>,
+    blocks_connected: AtomicU64,
+    events: broadcast::Sender<(u32, Event)>,
+    metrics: Metrics,
 }
 
 impl Bip300 {
-    pub fn new() -> Result<Self> {
-        let path = "./bip300.redb";
+    pub fn new(
+        path: &Path,
+        network: Network,
+        consensus_params: ConsensusParams,
+        start_block: StartBlock,
+        history_depth: Option<u32>,
+    ) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).into_diagnostic()?;
+        }
         let db = Database::create(path).into_diagnostic()?;
-        Ok(Self { db })
+        migrations::migrate(&db)?;
+        {
+            // Create the tables that are read before anything is written to them.
+            let write_txn = db.begin_write().into_diagnostic()?;
+            write_txn
+                .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?;
+            write_txn.open_table(PREVIOUS_VOTES).into_diagnostic()?;
+            write_txn
+                .open_table(BLOCK_HEIGHT_TO_EVENTS)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+                .into_diagnostic()?;
+            write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+            write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            write_txn
+                .open_table(PREVIOUS_VOTES_HISTORY)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(HISTORY_PRUNED_HEIGHT)
+                .into_diagnostic()?;
+            let mut stored_consensus_params =
+                write_txn.open_table(CONSENSUS_PARAMS).into_diagnostic()?;
+            if stored_consensus_params.get(()).into_diagnostic()?.is_none() {
+                stored_consensus_params
+                    .insert((), consensus_params)
+                    .into_diagnostic()?;
+            }
+            drop(stored_consensus_params);
+            write_txn.commit().into_diagnostic()?;
+        }
+        validation::check_metadata(&db)?;
+        let (events, _) = broadcast::channel(EVENTS_CHANNEL_CAPACITY);
+        Ok(Self {
+            db,
+            network,
+            consensus_params,
+            start_block,
+            history_depth,
+            blocks_connected: AtomicU64::new(0),
+            metrics: Metrics::new(),
+            events,
+        })
     }
 
-    pub fn connect_block(&self, block: &Block, height: u32) -> Result<()> {
-        println!("connect block");
-        // TODO: Check that there are no duplicate M2s.
-        let coinbase = &block.txdata[0];
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn consensus_params(&self) -> ConsensusParams {
+        self.consensus_params
+    }
+
+    /// Checks that the database was written with this version's schema and the configured
+    /// consensus params, that every stored block builds on the one stored below it, and that the
+    /// state is the one left by the tip, so that no block was applied without being committed as
+    /// the tip.
+    pub fn check_consistency(&self) -> Result<()> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let schema_version = migrations::stored_schema_version(&read_txn)?;
+        if schema_version != Some(SCHEMA_VERSION) {
+            return Err(miette!(
+                "database schema version is {schema_version:?}, expected {SCHEMA_VERSION}"
+            ));
+        }
+        let consensus_params = read_txn
+            .open_table(CONSENSUS_PARAMS)
+            .into_diagnostic()?
+            .get(())
+            .into_diagnostic()?
+            .map(|consensus_params| consensus_params.value());
+        if consensus_params != Some(self.consensus_params) {
+            return Err(miette!(
+                "database was built with consensus params {consensus_params:?}, expected {:?}",
+                self.consensus_params
+            ));
+        }
+        let block_height_to_block_info = read_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?;
+        let mut prev: Option<(u32, Hash256)> = None;
+        for entry in block_height_to_block_info.iter().into_diagnostic()? {
+            let (height, block_info) = entry.into_diagnostic()?;
+            let (height, block_info) = (height.value(), block_info.value());
+            if let Some((prev_height, prev_block_hash)) = prev {
+                if height != prev_height + 1 {
+                    return Err(miette!(
+                        "stored blocks skip from height {prev_height} to {height}"
+                    ));
+                }
+                if block_info.prev_block_hash != prev_block_hash {
+                    return Err(miette!(
+                        "stored block {} at height {height} doesn't build on block {}",
+                        BlockHash::from_byte_array(block_info.block_hash),
+                        BlockHash::from_byte_array(prev_block_hash)
+                    ));
+                }
+            }
+            prev = Some((height, block_info.block_hash));
+        }
+        drop(block_height_to_block_info);
+        drop(read_txn);
+        if let Some((height, tip)) = self.get_chain_tip()? {
+            // Computing the commitment needs a write transaction, which is aborted unchanged.
+            let write_txn = self.db.begin_write().into_diagnostic()?;
+            let state_commitment = commitment::state_commitment(&write_txn)?;
+            write_txn.abort().into_diagnostic()?;
+            if state_commitment != tip.state_commitment {
+                return Err(miette!(
+                    "state doesn't match the commitment of tip {} at height {height}",
+                    BlockHash::from_byte_array(tip.block_hash)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start_block(&self) -> StartBlock {
+        self.start_block
+    }
+
+    /// Number of blocks connected since this instance was opened.
+    pub fn blocks_connected(&self) -> u64 {
+        self.blocks_connected.load(Ordering::SeqCst)
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn get_chain_tip(&self) -> Result<Option<(u32, BlockInfo)>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let block_height_to_block_info = read_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?;
+        let tip = block_height_to_block_info
+            .last()
+            .into_diagnostic()?
+            .map(|(height, block_info)| (height.value(), block_info.value()));
+        Ok(tip)
+    }
+
+    pub fn get_block_info(&self, height: u32) -> Result<Option<BlockInfo>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let block_height_to_block_info = read_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?;
+        let block_info = block_height_to_block_info
+            .get(height)
+            .into_diagnostic()?
+            .map(|block_info| block_info.value());
+        Ok(block_info)
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<(u32, Event)> {
+        self.events.subscribe()
+    }
+
+    pub fn get_events(&self, from_height: u32) -> Result<Vec<(u32, Vec<Event>)>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let block_height_to_events = read_txn
+            .open_table(BLOCK_HEIGHT_TO_EVENTS)
+            .into_diagnostic()?;
+        let mut events = vec![];
+        for entry in block_height_to_events
+            .range(from_height..)
+            .into_diagnostic()?
+        {
+            let (height, block_events) = entry.into_diagnostic()?;
+            events.push((height.value(), block_events.value()));
+        }
+        Ok(events)
+    }
+
+    pub fn get_sidechain_proposals(
+        &self,
+        at_height: Option<u32>,
+    ) -> Result<Vec<(Hash256, SidechainProposal)>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let mut sidechain_proposals = vec![];
+        let Some(at_height) = at_height else {
+            let data_hash_to_sidechain_proposal = read_txn
+                .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+                .into_diagnostic()?;
+            for entry in data_hash_to_sidechain_proposal.iter().into_diagnostic()? {
+                let (data_hash, sidechain_proposal) = entry.into_diagnostic()?;
+                sidechain_proposals.push((*data_hash.value(), sidechain_proposal.value()));
+            }
+            return Ok(sidechain_proposals);
+        };
+        self.check_history_height(&read_txn, at_height)?;
+        let sidechain_proposal_history = read_txn
+            .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+            .into_diagnostic()?;
+        // Entries are sorted by data hash and then height, so the last one seen for each data
+        // hash is its version at `at_height`.
+        let mut latest: Option<(Hash256, Option<SidechainProposal>)> = None;
+        for entry in sidechain_proposal_history.iter().into_diagnostic()? {
+            let (key, sidechain_proposal) = entry.into_diagnostic()?;
+            let (data_hash, height) = key.value();
+            if height > at_height {
+                continue;
+            }
+            if let Some((latest_data_hash, Some(latest_proposal))) = latest.take() {
+                if latest_data_hash != *data_hash {
+                    sidechain_proposals.push((latest_data_hash, latest_proposal));
+                }
+            }
+            latest = Some((*data_hash, sidechain_proposal.value()));
+        }
+        if let Some((data_hash, Some(sidechain_proposal))) = latest {
+            sidechain_proposals.push((data_hash, sidechain_proposal));
+        }
+        Ok(sidechain_proposals)
+    }
+
+    pub fn get_sidechains(&self, at_height: Option<u32>) -> Result<Vec<Sidechain>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let mut sidechains = vec![];
+        let Some(at_height) = at_height else {
+            let sidechain_number_to_sidechain = read_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            for entry in sidechain_number_to_sidechain.iter().into_diagnostic()? {
+                let (_, sidechain) = entry.into_diagnostic()?;
+                sidechains.push(sidechain.value());
+            }
+            return Ok(sidechains);
+        };
+        self.check_history_height(&read_txn, at_height)?;
+        let sidechain_history = read_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+        let mut latest: Option<(u8, Option<Sidechain>)> = None;
+        for entry in sidechain_history.iter().into_diagnostic()? {
+            let (key, sidechain) = entry.into_diagnostic()?;
+            let (sidechain_number, height) = key.value();
+            if height > at_height {
+                continue;
+            }
+            if let Some((latest_sidechain_number, Some(latest_sidechain))) = latest.take() {
+                if latest_sidechain_number != sidechain_number {
+                    sidechains.push(latest_sidechain);
+                }
+            }
+            latest = Some((sidechain_number, sidechain.value()));
+        }
+        if let Some((_, Some(sidechain))) = latest {
+            sidechains.push(sidechain);
+        }
+        Ok(sidechains)
+    }
 
+    pub fn get_bundles(&self, sidechain_number: u8, at_height: Option<u32>) -> Result<Vec<Bundle>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let Some(at_height) = at_height else {
+            let bundle_index_to_bundle = read_txn
+                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                .into_diagnostic()?;
+            let bundles = bundles::sidechain_bundles(&bundle_index_to_bundle, sidechain_number)?;
+            return Ok(bundles.into_iter().map(|(_, bundle)| bundle).collect());
+        };
+        self.check_history_height(&read_txn, at_height)?;
+        let bundle_history = read_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+        let mut bundles = vec![];
+        let mut latest: Option<(u32, Option<Bundle>)> = None;
+        for entry in bundle_history
+            .range((sidechain_number, 0, 0)..=(sidechain_number, u32::MAX, u32::MAX))
+            .into_diagnostic()?
+        {
+            let (key, bundle) = entry.into_diagnostic()?;
+            let (_, bundle_index, height) = key.value();
+            if height > at_height {
+                continue;
+            }
+            if let Some((latest_bundle_index, Some(latest_bundle))) = latest.take() {
+                if latest_bundle_index != bundle_index {
+                    bundles.push(latest_bundle);
+                }
+            }
+            latest = Some((bundle_index, bundle.value()));
+        }
+        if let Some((_, Some(bundle))) = latest {
+            bundles.push(bundle);
+        }
+        Ok(bundles)
+    }
+
+    pub fn get_ctip(&self, sidechain_number: u8, at_height: Option<u32>) -> Result<Option<Ctip>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let ctip = match at_height {
+            None => read_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?
+                .get(sidechain_number)
+                .into_diagnostic()?
+                .map(|ctip| ctip.value()),
+            Some(at_height) => {
+                self.check_history_height(&read_txn, at_height)?;
+                read_txn
+                    .open_table(CTIP_HISTORY)
+                    .into_diagnostic()?
+                    .range((sidechain_number, 0)..=(sidechain_number, at_height))
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, ctip)| ctip.value())
+            }
+        };
+        Ok(ctip)
+    }
+
+    /// Votes, indexed by sidechain number, that an M4 in the next block would cast.
+    pub fn resolve_votes(&self, m4: &M4AckBundles) -> Result<Vec<Vote>> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let bundle_index_to_bundle = read_txn
+            .open_table(BUNDLE_INDEX_TO_BUNDLE)
+            .into_diagnostic()?;
+        let bundle_txid_to_bundle_index = read_txn
+            .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+            .into_diagnostic()?;
+        let previous_votes = read_txn.open_table(PREVIOUS_VOTES).into_diagnostic()?;
+        bundles::resolve_votes(
+            &bundle_index_to_bundle,
+            &bundle_txid_to_bundle_index,
+            &previous_votes,
+            m4,
+        )
+    }
+
+    /// Writes `initial_state` to a database that no blocks were connected to yet.
+    ///
+    /// The state is recorded in history just below the start block, so disconnecting the start
+    /// block goes back to it.
+    pub fn seed_state(&self, initial_state: InitialState) -> Result<()> {
+        if self.get_chain_tip()?.is_some() {
+            return Err(miette!("can't seed the state after blocks were connected"));
+        }
+        let Some(seed_height) = self.start_block.height.checked_sub(1) else {
+            return Err(miette!("can't seed the state when starting at height 0"));
+        };
         let write_txn = self.db.begin_write().into_diagnostic()?;
+        {
+            let mut sidechain_number_to_sidechain = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            let mut sidechain_history =
+                write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            for sidechain in initial_state.sidechains {
+                sidechain_number_to_sidechain
+                    .insert(sidechain.sidechain_number, &sidechain)
+                    .into_diagnostic()?;
+                sidechain_history
+                    .insert((sidechain.sidechain_number, seed_height), Some(sidechain))
+                    .into_diagnostic()?;
+            }
+            let mut sidechain_number_to_ctip = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?;
+            let mut ctip_history = write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            for (sidechain_number, ctip) in initial_state.ctips {
+                sidechain_number_to_ctip
+                    .insert(sidechain_number, &ctip)
+                    .into_diagnostic()?;
+                ctip_history
+                    .insert((sidechain_number, seed_height), Some(ctip))
+                    .into_diagnostic()?;
+            }
+        }
+        write_txn.commit().into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Reclaims space left by removed entries. Returns whether anything was reclaimed.
+    pub fn compact(&mut self) -> Result<bool> {
+        self.db.compact().into_diagnostic()
+    }
+
+    /// Checks that every stored value can be read back, which reads the whole database.
+    pub fn check_values(&self) -> Result<()> {
+        validation::check_values(&self.db)
+    }
+
+    /// Connects consecutive blocks in a single write transaction.
+    ///
+    /// Blocks before the start block are skipped, and blocks before the first invalid one are
+    /// still committed.
+    pub fn connect_blocks(&self, blocks: &[(u32, Block)]) -> Result<ConnectedBlocks> {
+        let skipped = blocks
+            .iter()
+            .take_while(|(height, _)| *height < self.start_block.height)
+            .count();
+        let blocks = &blocks[skipped..];
+        let mut write_txn = self.db.begin_write().into_diagnostic()?;
+        // Blocks are applied with their events, messages and the time they took to apply.
+        let mut applied = vec![];
+        let mut failure = None;
+        for (index, (height, block)) in blocks.iter().enumerate() {
+            if index > 0 && *height != blocks[index - 1].0 + 1 {
+                let err = miette!(
+                    "block at height {height} doesn't follow height {}",
+                    blocks[index - 1].0
+                );
+                self.metrics.block_rejected("height");
+                failure = Some((index, err));
+                break;
+            }
+            let started = Instant::now();
+            let mut messages = vec![];
+            match self.apply_block(&write_txn, block, *height, &mut messages) {
+                Ok(events) => applied.push((*height, events, messages, started.elapsed())),
+                Err(err) => {
+                    failure = Some((index, err));
+                    break;
+                }
+            }
+        }
+        if let Some((index, _)) = &failure {
+            // The invalid block may have been partially applied, so start over without it.
+            write_txn.abort().into_diagnostic()?;
+            write_txn = self.db.begin_write().into_diagnostic()?;
+            applied.clear();
+            for (height, block) in &blocks[..*index] {
+                let started = Instant::now();
+                let mut messages = vec![];
+                let events = self.apply_block(&write_txn, block, *height, &mut messages)?;
+                applied.push((*height, events, messages, started.elapsed()));
+            }
+        }
+        write_txn.commit().into_diagnostic()?;
+        let count = applied.len();
+        let last_height = applied.last().map(|(height, ..)| *height);
+        self.blocks_connected
+            .fetch_add(count as u64, Ordering::SeqCst);
+        for (height, events, messages, duration) in applied {
+            self.metrics.block_connected(duration, &messages);
+            self.publish_events(height, events);
+        }
+        let failure = failure.map(|(index, err)| (blocks[index].0, err));
+        Ok(ConnectedBlocks {
+            count,
+            last_height,
+            failure,
+        })
+    }
+
+    fn publish_events(&self, height: u32, events: Vec<Event>) {
+        for event in events {
+            // Sending only fails when nobody is subscribed.
+            let _ = self.events.send((height, event));
+        }
+    }
+
+    fn apply_block(
+        &self,
+        write_txn: &WriteTransaction,
+        block: &Block,
+        height: u32,
+        messages: &mut Vec<&'static str>,
+    ) -> Result<Vec<Event>> {
+        let _span = info_span!("block", height, block_hash = %block.block_hash()).entered();
+        let tip = write_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?
+            .last()
+            .into_diagnostic()?
+            .map(|(tip_height, tip)| (tip_height.value(), tip.value().block_hash));
+        let (expected_height, expected_prev_block_hash) = match tip {
+            Some((tip_height, tip_hash)) => (tip_height + 1, tip_hash),
+            None => (self.start_block.height, self.start_block.prev_block_hash),
+        };
+        if height != expected_height {
+            self.metrics.block_rejected("height");
+            return Err(miette!(
+                "block at height {height} doesn't extend the chain, expected height {expected_height}"
+            ));
+        }
+        if block.header.prev_blockhash.to_byte_array() != expected_prev_block_hash {
+            self.metrics.block_rejected("prev_block_hash");
+            return Err(miette!(
+                "block {} doesn't build on block {}",
+                block.block_hash(),
+                BlockHash::from_byte_array(expected_prev_block_hash)
+            ));
+        }
+        // TODO: Check that there are no duplicate M2s.
+        let Some((coinbase, transactions)) = block.txdata.split_first() else {
+            self.metrics.block_rejected("missing_coinbase");
+            return Err(miette!("block {} has no transactions", block.block_hash()));
+        };
+        let coinbase_txid = coinbase.txid();
+
+        let mut events = vec![];
         for output in &coinbase.output {
             match &parse_coinbase_script(&output.script_pubkey) {
                 Ok((_, message)) => {
+                    let _span = debug_span!(
+                        "bip300_message",
+                        txid = %coinbase_txid,
+                        sidechain_number = message_sidechain_number(message),
+                    )
+                    .entered();
+                    debug!(message = message_name(message), "applying coinbase message");
+                    messages.push(message_name(message));
                     match message {
                         CoinbaseMessage::M1ProposeSidechain {
                             sidechain_number,
@@ -71,6 +638,10 @@ impl Bip300 {
                             data_hash_to_sidechain_proposal
                                 .insert(&data_hash, sidechain_proposal)
                                 .into_diagnostic()?;
+                            events.push(Event::SidechainProposalCreated {
+                                sidechain_number: *sidechain_number,
+                                data_hash,
+                            });
                         }
                         CoinbaseMessage::M2AckSidechain {
                             sidechain_number,
@@ -91,12 +662,19 @@ impl Bip300 {
                                     data_hash_to_sidechain_proposal
                                         .insert(data_hash, &sidechain_proposal)
                                         .into_diagnostic()?;
+                                    events.push(Event::SidechainProposalVoted {
+                                        sidechain_number: *sidechain_number,
+                                        data_hash: *data_hash,
+                                        vote_count: sidechain_proposal.vote_count,
+                                    });
 
-                                    const USED_MAX_AGE: u16 = 26_300;
-                                    const USED_THRESHOLD: u16 = 13_150;
-
-                                    const UNUSED_MAX_AGE: u16 = 2016;
-                                    const UNUSED_THRESHOLD: u16 = UNUSED_MAX_AGE - 201;
+                                    let ConsensusParams {
+                                        used_max_age,
+                                        used_threshold,
+                                        unused_max_age,
+                                        unused_threshold,
+                                        ..
+                                    } = self.consensus_params;
 
                                     let sidechain_proposal_age =
                                         height - sidechain_proposal.proposal_height;
@@ -111,23 +689,27 @@ impl Bip300 {
                                         .is_some();
 
                                     let failed = used
-                                        && sidechain_proposal_age > USED_MAX_AGE as u32
-                                        && sidechain_proposal.vote_count <= USED_THRESHOLD
+                                        && sidechain_proposal_age > used_max_age
+                                        && sidechain_proposal.vote_count <= used_threshold
                                         || !used
-                                            && sidechain_proposal_age > UNUSED_MAX_AGE as u32
-                                            && sidechain_proposal.vote_count <= UNUSED_THRESHOLD;
+                                            && sidechain_proposal_age > unused_max_age
+                                            && sidechain_proposal.vote_count <= unused_threshold;
 
                                     let succeeded = used
-                                        && sidechain_proposal.vote_count > USED_THRESHOLD
+                                        && sidechain_proposal.vote_count > used_threshold
                                         || !used
-                                            && sidechain_proposal.vote_count > UNUSED_THRESHOLD;
+                                            && sidechain_proposal.vote_count > unused_threshold;
 
                                     if failed {
                                         data_hash_to_sidechain_proposal
                                             .remove(data_hash)
                                             .into_diagnostic()?;
+                                        events.push(Event::SidechainProposalExpired {
+                                            sidechain_number: *sidechain_number,
+                                            data_hash: *data_hash,
+                                        });
                                     } else if succeeded {
-                                        if sidechain_proposal.vote_count > USED_THRESHOLD {
+                                        if sidechain_proposal.vote_count > used_threshold {
                                             let sidechain = Sidechain {
                                                 sidechain_number: sidechain_proposal
                                                     .sidechain_number,
@@ -142,6 +724,10 @@ impl Bip300 {
                                             data_hash_to_sidechain_proposal
                                                 .remove(data_hash)
                                                 .into_diagnostic()?;
+                                            events.push(Event::SidechainActivated {
+                                                sidechain_number: *sidechain_number,
+                                                data_hash: *data_hash,
+                                            });
                                         }
                                     };
                                 }
@@ -151,114 +737,76 @@ impl Bip300 {
                             sidechain_number,
                             bundle_txid,
                         } => {
-                            let mut table = write_txn
-                                .open_table(SIDECHAIN_NUMBER_TO_BUNDLES)
-                                .into_diagnostic()?;
-                            let bundles = table
+                            let sidechain = write_txn
+                                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                                .into_diagnostic()?
                                 .get(sidechain_number)
                                 .into_diagnostic()?
-                                .map(|bundles| bundles.value());
-                            if let Some(mut bundles) = bundles {
-                                let bundle = Bundle {
-                                    bundle_txid: *bundle_txid,
-                                    vote_count: 0,
-                                };
-                                bundles.push(bundle);
-                                table.insert(sidechain_number, bundles).into_diagnostic()?;
-                            }
-                        }
-                        CoinbaseMessage::M4AckBundles(m4) => match m4 {
-                            M4AckBundles::LeadingBy50 => {
-                                todo!();
-                            }
-                            M4AckBundles::RepeatPrevious => {
-                                todo!();
-                            }
-                            M4AckBundles::OneByte { upvotes } => {
-                                let mut table = write_txn
-                                    .open_table(SIDECHAIN_NUMBER_TO_BUNDLES)
-                                    .into_diagnostic()?;
-                                for (sidechain_number, vote) in upvotes.iter().enumerate() {
-                                    if *vote == ABSTAIN_ONE_BYTE {
-                                        continue;
-                                    }
-                                    let bundles = table
-                                        .get(sidechain_number as u8)
-                                        .into_diagnostic()?
-                                        .map(|bundles| bundles.value());
-                                    if let Some(mut bundles) = bundles {
-                                        if *vote == ALARM_ONE_BYTE {
-                                            for bundle in &mut bundles {
-                                                if bundle.vote_count > 0 {
-                                                    bundle.vote_count -= 1;
-                                                }
-                                            }
-                                        } else if let Some(bundle) = bundles.get_mut(*vote as usize)
-                                        {
-                                            bundle.vote_count += 1;
-                                        }
-                                        table
-                                            .insert(sidechain_number as u8, bundles)
-                                            .into_diagnostic()?;
-                                    }
-                                }
+                                .map(|sidechain| sidechain.value());
+                            if sidechain.is_none() {
+                                continue;
                             }
-                            M4AckBundles::TwoBytes { upvotes } => {
-                                let mut table = write_txn
-                                    .open_table(SIDECHAIN_NUMBER_TO_BUNDLES)
-                                    .into_diagnostic()?;
-                                for (sidechain_number, vote) in upvotes.iter().enumerate() {
-                                    if *vote == ABSTAIN_TWO_BYTES {
-                                        continue;
-                                    }
-                                    let bundles = table
-                                        .get(sidechain_number as u8)
-                                        .into_diagnostic()?
-                                        .map(|bundles| bundles.value());
-                                    if let Some(mut bundles) = bundles {
-                                        if *vote == ALARM_TWO_BYTES {
-                                            for bundle in &mut bundles {
-                                                if bundle.vote_count > 0 {
-                                                    bundle.vote_count -= 1;
-                                                }
-                                            }
-                                        } else if let Some(bundle) = bundles.get_mut(*vote as usize)
-                                        {
-                                            bundle.vote_count += 1;
-                                        }
-                                        table
-                                            .insert(sidechain_number as u8, bundles)
-                                            .into_diagnostic()?;
-                                    }
-                                }
+                            let mut bundle_txid_to_bundle_index = write_txn
+                                .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+                                .into_diagnostic()?;
+                            if bundle_txid_to_bundle_index
+                                .get((*sidechain_number, bundle_txid))
+                                .into_diagnostic()?
+                                .is_some()
+                            {
+                                continue;
                             }
-                        },
+                            let mut bundle_index_to_bundle = write_txn
+                                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                                .into_diagnostic()?;
+                            let bundle_index = bundles::next_bundle_index(
+                                &bundle_index_to_bundle,
+                                *sidechain_number,
+                            )?;
+                            let bundle = Bundle {
+                                bundle_txid: *bundle_txid,
+                                vote_count: 0,
+                            };
+                            bundle_index_to_bundle
+                                .insert((*sidechain_number, bundle_index), bundle)
+                                .into_diagnostic()?;
+                            bundle_txid_to_bundle_index
+                                .insert((*sidechain_number, bundle_txid), bundle_index)
+                                .into_diagnostic()?;
+                            events.push(Event::BundleProposed {
+                                sidechain_number: *sidechain_number,
+                                bundle_txid: *bundle_txid,
+                            });
+                        }
+                        CoinbaseMessage::M4AckBundles(m4) => {
+                            self.ack_bundles(write_txn, m4, &mut events)?;
+                        }
                     }
                 }
-                Err(err) => {
-                    return Err(miette!("failed to parse coinbase script: {err}"));
+                Err(_) => {
+                    // Outputs that aren't BIP300 messages, such as the block reward, are ignored.
+                    continue;
                 }
             }
         }
 
-        for transaction in &block.txdata[1..] {
+        for transaction in transactions {
             // TODO: Check that there is only onen OP_DRIVECHAIN.
             let mut new_ctip = None;
             let mut sidechain_number = None;
             let mut new_total_value = None;
             for (vout, output) in transaction.output.iter().enumerate() {
-                let script = output.script_pubkey.to_bytes();
-                if script[0] == OP_DRIVECHAIN.to_u8() {
+                if let Some(drivechain_output) = drivechain_sidechain_number(&output.script_pubkey)
+                {
                     if new_ctip.is_some() {
+                        self.metrics.block_rejected("multiple_drivechain_outputs");
                         return Err(miette!("more than one OP_DRIVECHAIN output"));
                     }
-                    if script[1] != OP_PUSHBYTES_1.to_u8() {
-                        return Err(miette!("invalid OP_DRIVECHAIN output"));
-                    }
-                    if script[3] != OP_TRUE.to_u8() {
+                    let Some(drivechain_sidechain_number) = drivechain_output else {
+                        self.metrics.block_rejected("invalid_drivechain_output");
                         return Err(miette!("invalid OP_DRIVECHAIN output"));
-                    }
-                    sidechain_number = Some(script[2]);
+                    };
+                    sidechain_number = Some(drivechain_sidechain_number);
                     new_ctip = Some(OutPoint {
                         txid: transaction.txid(),
                         vout: vout as u32,
@@ -269,6 +817,12 @@ impl Bip300 {
             if let (Some(new_ctip), Some(sidechain_number), Some(new_total_value)) =
                 (new_ctip, sidechain_number, new_total_value)
             {
+                let _span = debug_span!(
+                    "bip300_message",
+                    txid = %transaction.txid(),
+                    sidechain_number,
+                )
+                .entered();
                 let mut sidechain_number_to_ctip = write_txn
                     .open_table(SIDECHAIN_NUMBER_TO_CTIP)
                     .into_diagnostic()?;
@@ -285,6 +839,7 @@ impl Bip300 {
                         }
                         old_ctip.value().value
                     } else {
+                        self.metrics.block_rejected("missing_ctip");
                         return Err(miette!("sidechain {sidechain_number} doesn't have ctip"));
                     }
                 };
@@ -294,32 +849,148 @@ impl Bip300 {
                         // deposit
                         // What would happen if new CTIP value is equal to old CTIP value?
                         // for now it is treated as a deposit of 0.
+                        debug!(
+                            value = new_total_value - old_total_value,
+                            "applying deposit"
+                        );
+                        messages.push("M5");
                         let new_ctip = Ctip {
                             outpoint: new_ctip,
                             value: new_total_value,
                         };
                         sidechain_number_to_ctip
-                            .insert(sidechain_number, new_ctip)
+                            .insert(sidechain_number, &new_ctip)
                             .into_diagnostic()?;
+                        events.push(Event::Deposit {
+                            sidechain_number,
+                            outpoint: new_ctip.outpoint,
+                            value: new_total_value - old_total_value,
+                            total_value: new_total_value,
+                        });
                     } else {
                         // M6
                         // set correspondidng withdrawal bundle hash as spent
-                        todo!();
+                        debug!(
+                            value = old_total_value - new_total_value,
+                            "applying withdrawal"
+                        );
+                        messages.push("M6");
+                        let bundle_txid: Hash256 = transaction.txid().to_byte_array();
+                        let bundle_index = write_txn
+                            .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+                            .into_diagnostic()?
+                            .get((sidechain_number, &bundle_txid))
+                            .into_diagnostic()?
+                            .map(|bundle_index| bundle_index.value());
+                        let approved_bundle = match bundle_index {
+                            Some(bundle_index) => write_txn
+                                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                                .into_diagnostic()?
+                                .get((sidechain_number, bundle_index))
+                                .into_diagnostic()?
+                                .filter(|bundle| {
+                                    bundle.value().vote_count
+                                        > self.consensus_params.bundle_threshold
+                                })
+                                .map(|_| bundle_index),
+                            None => None,
+                        };
+                        let Some(approved_bundle) = approved_bundle else {
+                            self.metrics.block_rejected("unapproved_withdrawal");
+                            return Err(miette!(
+                                "withdrawal {} for sidechain {sidechain_number} wasn't approved",
+                                transaction.txid()
+                            ));
+                        };
+                        bundles::remove_bundle(write_txn, sidechain_number, approved_bundle)?;
+                        let new_ctip = Ctip {
+                            outpoint: new_ctip,
+                            value: new_total_value,
+                        };
+                        sidechain_number_to_ctip
+                            .insert(sidechain_number, new_ctip)
+                            .into_diagnostic()?;
+                        events.push(Event::Withdrawal {
+                            sidechain_number,
+                            bundle_txid,
+                            value: old_total_value - new_total_value,
+                            total_value: new_total_value,
+                        });
                     }
                 } else {
+                    self.metrics.block_rejected("ctip_not_spent");
                     return Err(miette!(
                         "old ctip wasn't spent for sidechain {sidechain_number}"
                     ));
                 }
             }
-            dbg!(transaction);
         }
-        write_txn.commit().into_diagnostic()?;
-        Ok(())
+        events.push(Event::BlockConnected {
+            block_hash: block.block_hash().to_byte_array(),
+        });
+        debug!(events = events.len(), "connected block");
+        self.record_history(write_txn, height, &events)?;
+        {
+            let mut block_height_to_events = write_txn
+                .open_table(BLOCK_HEIGHT_TO_EVENTS)
+                .into_diagnostic()?;
+            block_height_to_events
+                .insert(height, &events)
+                .into_diagnostic()?;
+            let mut block_height_to_block_info = write_txn
+                .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+                .into_diagnostic()?;
+            let block_info = BlockInfo {
+                block_hash: block.block_hash().to_byte_array(),
+                prev_block_hash: block.header.prev_blockhash.to_byte_array(),
+                state_commitment: commitment::state_commitment(write_txn)?,
+            };
+            block_height_to_block_info
+                .insert(height, block_info)
+                .into_diagnostic()?;
+        }
+        Ok(events)
     }
 
     pub fn disconnect_block(&self, block: &Block) -> Result<()> {
-        todo!();
+        let block_hash = block.block_hash().to_byte_array();
+        let write_txn = self.db.begin_write().into_diagnostic()?;
+        let height = {
+            let mut block_height_to_block_info = write_txn
+                .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+                .into_diagnostic()?;
+            let tip = block_height_to_block_info
+                .last()
+                .into_diagnostic()?
+                .map(|(height, block_info)| (height.value(), block_info.value()));
+            let Some((height, tip)) = tip else {
+                return Err(miette!("there are no blocks to disconnect"));
+            };
+            if tip.block_hash != block_hash {
+                return Err(miette!("block {} is not the chain tip", block.block_hash()));
+            }
+            block_height_to_block_info
+                .remove(height)
+                .into_diagnostic()?;
+            height
+        };
+        let events = write_txn
+            .open_table(BLOCK_HEIGHT_TO_EVENTS)
+            .into_diagnostic()?
+            .remove(height)
+            .into_diagnostic()?
+            .map(|events| events.value());
+        let Some(events) = events else {
+            return Err(miette!(
+                "history for height {height} was pruned, can't disconnect it"
+            ));
+        };
+        self.revert_history(&write_txn, height, &events)?;
+        write_txn.commit().into_diagnostic()?;
+        info!(height, block_hash = %block.block_hash(), "disconnected block");
+        self.metrics.block_disconnected();
+        self.publish_events(height, vec![Event::BlockDisconnected { block_hash }]);
+        Ok(())
     }
 
     pub fn is_block_valid(&self, block: &Block) -> Result<()> {
@@ -331,3 +1002,243 @@ impl Bip300 {
         todo!();
     }
 }
+
+/// Sidechain number of an OP_DRIVECHAIN output, which must be `OP_DRIVECHAIN <sidechain number>
+/// OP_TRUE`. None if `script` isn't an OP_DRIVECHAIN output, and `Some(None)` if it is malformed.
+pub fn drivechain_sidechain_number(script: &Script) -> Option<Option<u8>> {
+    let script = script.as_bytes();
+    if script.first() != Some(&OP_DRIVECHAIN.to_u8()) {
+        return None;
+    }
+    if script.len() != 4 || script[1] != OP_PUSHBYTES_1.to_u8() || script[3] != OP_TRUE.to_u8() {
+        return Some(None);
+    }
+    Some(Some(script[2]))
+}
+
+/// Script of the OP_DRIVECHAIN output holding the funds of `sidechain_number`.
+pub fn drivechain_script(sidechain_number: u8) -> ScriptBuf {
+    ScriptBuf::from_bytes(vec![
+        OP_DRIVECHAIN.to_u8(),
+        OP_PUSHBYTES_1.to_u8(),
+        sidechain_number,
+        OP_TRUE.to_u8(),
+    ])
+}
+
+pub fn message_name(message: &CoinbaseMessage) -> &'static str {
+    match message {
+        CoinbaseMessage::M1ProposeSidechain { .. } => "M1",
+        CoinbaseMessage::M2AckSidechain { .. } => "M2",
+        CoinbaseMessage::M3ProposeBundle { .. } => "M3",
+        CoinbaseMessage::M4AckBundles(_) => "M4",
+    }
+}
+
+/// Sidechain a coinbase message is about. M4 votes on every sidechain at once.
+pub fn message_sidechain_number(message: &CoinbaseMessage) -> Option<u8> {
+    match message {
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number, ..
+        }
+        | CoinbaseMessage::M2AckSidechain {
+            sidechain_number, ..
+        }
+        | CoinbaseMessage::M3ProposeBundle {
+            sidechain_number, ..
+        } => Some(*sidechain_number),
+        CoinbaseMessage::M4AckBundles(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use bitcoin::hashes::hash160;
+    use bitcoin::script::PushBytes;
+    use bitcoin::{Amount, ScriptBuf, TxOut, WPubkeyHash};
+
+    use super::*;
+    use crate::test_utils::{
+        block, connect_block, ctip, sidechain, spend_ctip, TestChain, START_HEIGHT,
+    };
+
+    #[test]
+    fn coinbase_outputs_that_arent_messages_are_ignored() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let proposal = CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: 1,
+            data: vec![1],
+        };
+        let mut block = block(None, vec![proposal], vec![]);
+        let reward = TxOut {
+            value: Amount::from_btc(6.25).unwrap(),
+            script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::from_raw_hash(
+                hash160::Hash::all_zeros(),
+            )),
+        };
+        let mut witness_commitment = vec![0xaa, 0x21, 0xa9, 0xed];
+        witness_commitment.extend([0; 32]);
+        let witness_commitment = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(
+                <&PushBytes>::try_from(witness_commitment.as_slice()).unwrap(),
+            ),
+        };
+        block.txdata[0].output.insert(0, reward);
+        block.txdata[0].output.push(witness_commitment);
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+
+        connect_block(&chain.bip300, &block, chain.next_height())?;
+        assert_eq!(chain.bip300.get_sidechain_proposals(None)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_blocks_reports_the_last_connected_height() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let before_start = block(None, vec![], vec![]);
+        let first = chain.next_block(vec![], vec![]);
+        let second = block(Some(&first), vec![], vec![]);
+        let third = block(Some(&second), vec![], vec![]);
+        let blocks = [
+            (START_HEIGHT - 1, before_start),
+            (START_HEIGHT, first),
+            (START_HEIGHT + 1, second),
+            // Not the next height.
+            (START_HEIGHT + 3, third),
+        ];
+
+        let connected = chain.bip300.connect_blocks(&blocks)?;
+        assert_eq!(connected.count, 2);
+        assert_eq!(connected.last_height, Some(START_HEIGHT + 1));
+        assert_eq!(
+            connected.failure.map(|(height, _)| height),
+            Some(START_HEIGHT + 3)
+        );
+        assert_eq!(
+            chain.bip300.get_chain_tip()?.map(|(height, _)| height),
+            Some(START_HEIGHT + 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_without_transactions_are_rejected() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let mut block = chain.next_block(vec![], vec![]);
+        block.txdata.clear();
+        let err = connect_block(&chain.bip300, &block, chain.next_height()).unwrap_err();
+        assert!(err.to_string().contains("has no transactions"));
+        assert!(chain.bip300.get_chain_tip()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_ctips_can_be_read_back() -> Result<()> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+        })?;
+        assert_eq!(chain.bip300.get_ctip(0, None)?, Some(ctip(1, 1000)));
+        assert_eq!(chain.bip300.get_ctip(1, None)?, None);
+        chain.connect(vec![], vec![])?;
+        assert_eq!(
+            chain.bip300.get_ctip(0, Some(START_HEIGHT))?,
+            Some(ctip(1, 1000))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn databases_are_checked_against_the_configured_consensus_params() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        chain.bip300.check_consistency()?;
+        let path = chain.dir.path().join("bip300.redb");
+        drop(Arc::into_inner(chain.bip300).unwrap());
+        let consensus_params = ConsensusParams {
+            bundle_threshold: 10,
+            ..ConsensusParams::default()
+        };
+        let bip300 = Bip300::new(
+            &path,
+            Network::Regtest,
+            consensus_params,
+            StartBlock::default(),
+            None,
+        )?;
+        assert!(bip300.check_consistency().is_err());
+        Ok(())
+    }
+
+    /// Sidechain 0 with a ctip of 1000 and a bundle withdrawing 400 of it, upvoted `votes` times,
+    /// where bundles need more than two votes.
+    fn chain_with_bundle(votes: usize) -> Result<(TestChain, Transaction)> {
+        let mut chain = TestChain::with_consensus_params(
+            InitialState {
+                sidechains: vec![sidechain(0)],
+                ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+            },
+            ConsensusParams {
+                bundle_threshold: 2,
+                ..ConsensusParams::default()
+            },
+        )?;
+        let withdrawal = spend_ctip(0, &ctip(1, 1000), 600);
+        chain.connect(
+            vec![CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: 0,
+                bundle_txid: withdrawal.txid().to_byte_array(),
+            }],
+            vec![],
+        )?;
+        for _ in 0..votes {
+            chain.connect(
+                vec![CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                    upvotes: vec![0],
+                })],
+                vec![],
+            )?;
+        }
+        Ok((chain, withdrawal))
+    }
+
+    #[test]
+    fn approved_bundles_are_paid_out() -> Result<()> {
+        let (mut chain, withdrawal) = chain_with_bundle(3)?;
+        chain.connect(vec![], vec![withdrawal.clone()])?;
+        let new_ctip = chain.bip300.get_ctip(0, None)?.unwrap();
+        assert_eq!(new_ctip.outpoint.txid, withdrawal.txid());
+        assert_eq!(new_ctip.value, 600);
+        assert!(chain.bip300.get_bundles(0, None)?.is_empty());
+        let (_, events) = chain.bip300.get_events(chain.next_height() - 1)?.remove(0);
+        assert_eq!(
+            events[0],
+            Event::Withdrawal {
+                sidechain_number: 0,
+                bundle_txid: withdrawal.txid().to_byte_array(),
+                value: 400,
+                total_value: 600,
+            }
+        );
+
+        // Disconnecting the withdrawal restores the bundle and the ctip.
+        chain.disconnect()?;
+        assert_eq!(chain.bip300.get_ctip(0, None)?, Some(ctip(1, 1000)));
+        assert_eq!(chain.bip300.get_bundles(0, None)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unapproved_bundles_are_not_paid_out() -> Result<()> {
+        let (mut chain, withdrawal) = chain_with_bundle(2)?;
+        assert!(chain.connect(vec![], vec![withdrawal]).is_err());
+        // A withdrawal without a bundle isn't paid out either.
+        let other_withdrawal = spend_ctip(0, &ctip(1, 1000), 500);
+        assert!(chain.connect(vec![], vec![other_withdrawal]).is_err());
+        assert_eq!(chain.bip300.get_ctip(0, None)?, Some(ctip(1, 1000)));
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use super::*;
+use bip300_messages::{ABSTAIN_ONE_BYTE, ABSTAIN_TWO_BYTES, ALARM_ONE_BYTE, ALARM_TWO_BYTES};
+use redb::Table;
+use std::collections::BTreeMap;
+
+// Bundles of a sidechain are numbered from 0 in the order they were proposed, which is the index
+// M4 votes refer to. Indexes stay contiguous: when a bundle is paid out, the bundles after it
+// move down by one.
+
+/// Votes a bundle must lead every other bundle of its sidechain by for a LeadingBy50 M4 to upvote
+/// it.
+const LEADING_BY_50_MARGIN: u16 = 50;
+
+/// Index the next bundle proposed for `sidechain_number` gets.
+pub(super) fn next_bundle_index(
+    bundle_index_to_bundle: &Table<(u8, u32), Bundle>,
+    sidechain_number: u8,
+) -> Result<u32> {
+    let next_bundle_index = bundle_index_to_bundle
+        .range((sidechain_number, 0)..=(sidechain_number, u32::MAX))
+        .into_diagnostic()?
+        .next_back()
+        .transpose()
+        .into_diagnostic()?
+        .map(|(key, _)| key.value().1 + 1)
+        .unwrap_or(0);
+    Ok(next_bundle_index)
+}
+
+/// All bundles of `sidechain_number` with their indexes.
+pub(super) fn sidechain_bundles(
+    bundle_index_to_bundle: &impl ReadableTable<(u8, u32), Bundle>,
+    sidechain_number: u8,
+) -> Result<Vec<(u32, Bundle)>> {
+    let mut bundles = vec![];
+    for entry in bundle_index_to_bundle
+        .range((sidechain_number, 0)..=(sidechain_number, u32::MAX))
+        .into_diagnostic()?
+    {
+        let (key, bundle) = entry.into_diagnostic()?;
+        bundles.push((key.value().1, bundle.value()));
+    }
+    Ok(bundles)
+}
+
+/// The votes of `m4`, indexed by sidechain number, without trailing abstentions.
+///
+/// Upvotes of bundles that don't exist are abstentions. RepeatPrevious repeats the votes of the
+/// last M4, leaving out bundles that were paid out since.
+pub(super) fn resolve_votes(
+    bundle_index_to_bundle: &impl ReadableTable<(u8, u32), Bundle>,
+    bundle_txid_to_bundle_index: &impl ReadableTable<(u8, &'static Hash256), u32>,
+    previous_votes: &impl ReadableTable<(), Vec<Vote>>,
+    m4: &M4AckBundles,
+) -> Result<Vec<Vote>> {
+    let mut votes = match m4 {
+        M4AckBundles::RepeatPrevious => {
+            let previous_votes = previous_votes
+                .get(())
+                .into_diagnostic()?
+                .map(|previous_votes| previous_votes.value())
+                .unwrap_or_default();
+            let mut votes = vec![];
+            for (sidechain_number, vote) in (0..=u8::MAX).zip(previous_votes) {
+                let vote = match vote {
+                    Vote::Upvote { bundle_txid } => {
+                        let exists = bundle_txid_to_bundle_index
+                            .get((sidechain_number, &bundle_txid))
+                            .into_diagnostic()?
+                            .is_some();
+                        if exists {
+                            vote
+                        } else {
+                            Vote::Abstain
+                        }
+                    }
+                    Vote::Abstain | Vote::Alarm => vote,
+                };
+                votes.push(vote);
+            }
+            votes
+        }
+        M4AckBundles::LeadingBy50 => leading_by_50_votes(bundle_index_to_bundle)?,
+        M4AckBundles::OneByte { upvotes } => {
+            let upvotes = upvotes.iter().map(|upvote| match *upvote {
+                ABSTAIN_ONE_BYTE => None,
+                ALARM_ONE_BYTE => Some(None),
+                bundle_index => Some(Some(bundle_index as u32)),
+            });
+            index_votes(bundle_index_to_bundle, upvotes)?
+        }
+        M4AckBundles::TwoBytes { upvotes } => {
+            let upvotes = upvotes.iter().map(|upvote| match *upvote {
+                ABSTAIN_TWO_BYTES => None,
+                ALARM_TWO_BYTES => Some(None),
+                bundle_index => Some(Some(bundle_index as u32)),
+            });
+            index_votes(bundle_index_to_bundle, upvotes)?
+        }
+    };
+    while votes.last() == Some(&Vote::Abstain) {
+        votes.pop();
+    }
+    Ok(votes)
+}
+
+/// Votes of upvote vectors, given as None for abstentions, `Some(None)` for alarms and the bundle
+/// index otherwise.
+fn index_votes(
+    bundle_index_to_bundle: &impl ReadableTable<(u8, u32), Bundle>,
+    upvotes: impl Iterator<Item = Option<Option<u32>>>,
+) -> Result<Vec<Vote>> {
+    let mut votes = vec![];
+    for (sidechain_number, upvote) in (0..=u8::MAX).zip(upvotes) {
+        let vote = match upvote {
+            None => Vote::Abstain,
+            Some(None) => Vote::Alarm,
+            Some(Some(bundle_index)) => bundle_index_to_bundle
+                .get((sidechain_number, bundle_index))
+                .into_diagnostic()?
+                .map_or(Vote::Abstain, |bundle| Vote::Upvote {
+                    bundle_txid: bundle.value().bundle_txid,
+                }),
+        };
+        votes.push(vote);
+    }
+    Ok(votes)
+}
+
+/// Upvotes the bundle of every sidechain that leads all of its other bundles by at least
+/// `LEADING_BY_50_MARGIN` votes, abstaining on the others.
+fn leading_by_50_votes(
+    bundle_index_to_bundle: &impl ReadableTable<(u8, u32), Bundle>,
+) -> Result<Vec<Vote>> {
+    // The most and second most votes of each sidechain's bundles, with the leading bundle.
+    let mut leaders: BTreeMap<u8, (Bundle, u16)> = BTreeMap::new();
+    for entry in bundle_index_to_bundle.iter().into_diagnostic()? {
+        let (key, bundle) = entry.into_diagnostic()?;
+        let (sidechain_number, _) = key.value();
+        let bundle = bundle.value();
+        match leaders.get_mut(&sidechain_number) {
+            None => {
+                leaders.insert(sidechain_number, (bundle, 0));
+            }
+            Some((leader, runner_up)) => {
+                if bundle.vote_count > leader.vote_count {
+                    *runner_up = leader.vote_count;
+                    *leader = bundle;
+                } else {
+                    *runner_up = (*runner_up).max(bundle.vote_count);
+                }
+            }
+        }
+    }
+    let mut votes = vec![];
+    for (sidechain_number, (leader, runner_up)) in leaders {
+        if leader.vote_count - runner_up < LEADING_BY_50_MARGIN {
+            continue;
+        }
+        votes.resize(sidechain_number as usize, Vote::Abstain);
+        votes.push(Vote::Upvote {
+            bundle_txid: leader.bundle_txid,
+        });
+    }
+    Ok(votes)
+}
+
+impl Bip300 {
+    /// Applies an M4, and keeps its votes for the next M4 that repeats them.
+    pub(super) fn ack_bundles(
+        &self,
+        write_txn: &WriteTransaction,
+        m4: &M4AckBundles,
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        let mut bundle_index_to_bundle = write_txn
+            .open_table(BUNDLE_INDEX_TO_BUNDLE)
+            .into_diagnostic()?;
+        let bundle_txid_to_bundle_index = write_txn
+            .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+            .into_diagnostic()?;
+        let mut previous_votes = write_txn.open_table(PREVIOUS_VOTES).into_diagnostic()?;
+        let votes = resolve_votes(
+            &bundle_index_to_bundle,
+            &bundle_txid_to_bundle_index,
+            &previous_votes,
+            m4,
+        )?;
+        for (sidechain_number, vote) in (0..=u8::MAX).zip(&votes) {
+            match vote {
+                Vote::Abstain => {}
+                Vote::Alarm => {
+                    alarm_bundles(&mut bundle_index_to_bundle, sidechain_number, events)?
+                }
+                Vote::Upvote { bundle_txid } => {
+                    let bundle_index = bundle_txid_to_bundle_index
+                        .get((sidechain_number, bundle_txid))
+                        .into_diagnostic()?
+                        .map(|bundle_index| bundle_index.value());
+                    if let Some(bundle_index) = bundle_index {
+                        self.upvote_bundle(
+                            &mut bundle_index_to_bundle,
+                            sidechain_number,
+                            bundle_index,
+                            events,
+                        )?;
+                    }
+                }
+            }
+        }
+        if votes.is_empty() {
+            previous_votes.remove(()).into_diagnostic()?;
+        } else {
+            previous_votes.insert((), votes).into_diagnostic()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn upvote_bundle(
+        &self,
+        bundle_index_to_bundle: &mut Table<(u8, u32), Bundle>,
+        sidechain_number: u8,
+        bundle_index: u32,
+        events: &mut Vec<Event>,
+    ) -> Result<()> {
+        let bundle = bundle_index_to_bundle
+            .get((sidechain_number, bundle_index))
+            .into_diagnostic()?
+            .map(|bundle| bundle.value());
+        let Some(mut bundle) = bundle else {
+            return Ok(());
+        };
+        bundle.vote_count += 1;
+        bundle_index_to_bundle
+            .insert((sidechain_number, bundle_index), &bundle)
+            .into_diagnostic()?;
+        events.push(Event::BundleVoted {
+            sidechain_number,
+            bundle_txid: bundle.bundle_txid,
+            vote_count: bundle.vote_count,
+        });
+        if bundle.vote_count == self.consensus_params.bundle_threshold + 1 {
+            events.push(Event::BundleApproved {
+                sidechain_number,
+                bundle_txid: bundle.bundle_txid,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Takes a vote away from every bundle of `sidechain_number`.
+pub(super) fn alarm_bundles(
+    bundle_index_to_bundle: &mut Table<(u8, u32), Bundle>,
+    sidechain_number: u8,
+    events: &mut Vec<Event>,
+) -> Result<()> {
+    for (bundle_index, mut bundle) in sidechain_bundles(bundle_index_to_bundle, sidechain_number)? {
+        if bundle.vote_count == 0 {
+            continue;
+        }
+        bundle.vote_count -= 1;
+        bundle_index_to_bundle
+            .insert((sidechain_number, bundle_index), &bundle)
+            .into_diagnostic()?;
+        events.push(Event::BundleVoted {
+            sidechain_number,
+            bundle_txid: bundle.bundle_txid,
+            vote_count: bundle.vote_count,
+        });
+    }
+    Ok(())
+}
+
+/// Removes a paid out bundle, moving the bundles proposed after it down by one index.
+pub(super) fn remove_bundle(
+    write_txn: &WriteTransaction,
+    sidechain_number: u8,
+    bundle_index: u32,
+) -> Result<()> {
+    let mut bundle_index_to_bundle = write_txn
+        .open_table(BUNDLE_INDEX_TO_BUNDLE)
+        .into_diagnostic()?;
+    let mut bundle_txid_to_bundle_index = write_txn
+        .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+        .into_diagnostic()?;
+    let Some(bundle) = bundle_index_to_bundle
+        .remove((sidechain_number, bundle_index))
+        .into_diagnostic()?
+        .map(|bundle| bundle.value())
+    else {
+        return Ok(());
+    };
+    bundle_txid_to_bundle_index
+        .remove((sidechain_number, &bundle.bundle_txid))
+        .into_diagnostic()?;
+    let mut later_bundles = vec![];
+    for entry in bundle_index_to_bundle
+        .range((sidechain_number, bundle_index + 1)..=(sidechain_number, u32::MAX))
+        .into_diagnostic()?
+    {
+        let (key, bundle) = entry.into_diagnostic()?;
+        later_bundles.push((key.value().1, bundle.value()));
+    }
+    for (later_bundle_index, later_bundle) in later_bundles {
+        bundle_index_to_bundle
+            .remove((sidechain_number, later_bundle_index))
+            .into_diagnostic()?;
+        bundle_index_to_bundle
+            .insert((sidechain_number, later_bundle_index - 1), &later_bundle)
+            .into_diagnostic()?;
+        bundle_txid_to_bundle_index
+            .insert(
+                (sidechain_number, &later_bundle.bundle_txid),
+                later_bundle_index - 1,
+            )
+            .into_diagnostic()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::{CoinbaseMessage, M4AckBundles, ABSTAIN_ONE_BYTE, ALARM_ONE_BYTE};
+    use miette::Result;
+
+    use crate::test_utils::{hash, TestChain};
+    use crate::types::Hash256;
+
+    fn propose_bundle(sidechain_number: u8, bundle_txid: Hash256) -> CoinbaseMessage {
+        CoinbaseMessage::M3ProposeBundle {
+            sidechain_number,
+            bundle_txid,
+        }
+    }
+
+    fn upvotes(upvotes: &[u8]) -> CoinbaseMessage {
+        CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+            upvotes: upvotes.to_vec(),
+        })
+    }
+
+    fn vote_counts(chain: &TestChain, sidechain_number: u8) -> Result<Vec<u16>> {
+        let bundles = chain.bip300.get_bundles(sidechain_number, None)?;
+        Ok(bundles.iter().map(|bundle| bundle.vote_count).collect())
+    }
+
+    #[test]
+    fn repeat_previous_repeats_the_last_votes() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0, 1])?;
+        chain.connect(
+            vec![propose_bundle(0, hash(1)), propose_bundle(1, hash(2))],
+            vec![],
+        )?;
+        chain.connect(vec![upvotes(&[0, ABSTAIN_ONE_BYTE])], vec![])?;
+        for _ in 0..2 {
+            chain.connect(
+                vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+                vec![],
+            )?;
+        }
+        assert_eq!(vote_counts(&chain, 0)?, [3]);
+        assert_eq!(vote_counts(&chain, 1)?, [0]);
+        Ok(())
+    }
+
+    #[test]
+    fn repeat_previous_repeats_alarms() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(vec![propose_bundle(0, hash(1))], vec![])?;
+        for _ in 0..3 {
+            chain.connect(vec![upvotes(&[0])], vec![])?;
+        }
+        chain.connect(vec![upvotes(&[ALARM_ONE_BYTE])], vec![])?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+            vec![],
+        )?;
+        assert_eq!(vote_counts(&chain, 0)?, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn repeat_previous_after_abstaining_everywhere_abstains() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(vec![propose_bundle(0, hash(1))], vec![])?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+            vec![],
+        )?;
+        assert_eq!(vote_counts(&chain, 0)?, [0]);
+        chain.connect(vec![upvotes(&[0])], vec![])?;
+        chain.connect(vec![upvotes(&[ABSTAIN_ONE_BYTE])], vec![])?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+            vec![],
+        )?;
+        assert_eq!(vote_counts(&chain, 0)?, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn leading_by_50_upvotes_bundles_leading_by_the_margin() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0, 1])?;
+        chain.connect(
+            vec![
+                propose_bundle(0, hash(1)),
+                propose_bundle(0, hash(2)),
+                propose_bundle(1, hash(3)),
+            ],
+            vec![],
+        )?;
+        for _ in 0..50 {
+            chain.connect(vec![upvotes(&[0, 0])], vec![])?;
+        }
+        chain.connect(vec![upvotes(&[1])], vec![])?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::LeadingBy50)],
+            vec![],
+        )?;
+        // The first bundle of sidechain 0 only leads by 49 votes.
+        assert_eq!(vote_counts(&chain, 0)?, [50, 1]);
+        assert_eq!(vote_counts(&chain, 1)?, [51]);
+        Ok(())
+    }
+
+    #[test]
+    fn disconnecting_restores_the_previous_votes() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(vec![propose_bundle(0, hash(1))], vec![])?;
+        chain.connect(vec![upvotes(&[0])], vec![])?;
+        chain.connect(vec![upvotes(&[ABSTAIN_ONE_BYTE])], vec![])?;
+        chain.disconnect()?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+            vec![],
+        )?;
+        assert_eq!(vote_counts(&chain, 0)?, [2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use super::*;
+
+/// Hashes the full BIP300 state as seen by `write_txn`.
+///
+/// The state is serialized in a fixed order that doesn't depend on how it is stored: proposals
+/// by data hash, then sidechains, bundles and ctips by sidechain number, then the previous votes,
+/// each a byte for abstain (0), alarm (1) or upvote (2) followed by the txid of upvoted bundles.
+/// Each section starts with its number of entries, and all integers are big endian.
+pub(super) fn state_commitment(write_txn: &WriteTransaction) -> Result<Hash256> {
+    let mut data = vec![];
+
+    let data_hash_to_sidechain_proposal = write_txn
+        .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+        .into_diagnostic()?;
+    data.extend((data_hash_to_sidechain_proposal.len().into_diagnostic()? as u32).to_be_bytes());
+    for entry in data_hash_to_sidechain_proposal.iter().into_diagnostic()? {
+        let (data_hash, sidechain_proposal) = entry.into_diagnostic()?;
+        let sidechain_proposal = sidechain_proposal.value();
+        data.extend(data_hash.value());
+        data.push(sidechain_proposal.sidechain_number);
+        data.extend(sidechain_proposal.vote_count.to_be_bytes());
+        data.extend(sidechain_proposal.proposal_height.to_be_bytes());
+        data.extend((sidechain_proposal.data.len() as u32).to_be_bytes());
+        data.extend(sidechain_proposal.data);
+    }
+
+    let sidechain_number_to_sidechain = write_txn
+        .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+        .into_diagnostic()?;
+    data.extend((sidechain_number_to_sidechain.len().into_diagnostic()? as u32).to_be_bytes());
+    for entry in sidechain_number_to_sidechain.iter().into_diagnostic()? {
+        let (_, sidechain) = entry.into_diagnostic()?;
+        let sidechain = sidechain.value();
+        data.push(sidechain.sidechain_number);
+        data.extend(sidechain.vote_count.to_be_bytes());
+        data.extend(sidechain.proposal_height.to_be_bytes());
+        data.extend(sidechain.activation_height.to_be_bytes());
+        data.extend((sidechain.data.len() as u32).to_be_bytes());
+        data.extend(sidechain.data);
+    }
+
+    let bundle_index_to_bundle = write_txn
+        .open_table(BUNDLE_INDEX_TO_BUNDLE)
+        .into_diagnostic()?;
+    let mut bundles: Vec<(u8, Vec<Bundle>)> = vec![];
+    for entry in bundle_index_to_bundle.iter().into_diagnostic()? {
+        let (key, bundle) = entry.into_diagnostic()?;
+        let (sidechain_number, _) = key.value();
+        match bundles.last_mut() {
+            Some((last_sidechain_number, sidechain_bundles))
+                if *last_sidechain_number == sidechain_number =>
+            {
+                sidechain_bundles.push(bundle.value())
+            }
+            _ => bundles.push((sidechain_number, vec![bundle.value()])),
+        }
+    }
+    data.extend((bundles.len() as u32).to_be_bytes());
+    for (sidechain_number, sidechain_bundles) in bundles {
+        data.push(sidechain_number);
+        data.extend((sidechain_bundles.len() as u32).to_be_bytes());
+        for bundle in sidechain_bundles {
+            data.extend(bundle.bundle_txid);
+            data.extend(bundle.vote_count.to_be_bytes());
+        }
+    }
+
+    let sidechain_number_to_ctip = write_txn
+        .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+        .into_diagnostic()?;
+    data.extend((sidechain_number_to_ctip.len().into_diagnostic()? as u32).to_be_bytes());
+    for entry in sidechain_number_to_ctip.iter().into_diagnostic()? {
+        let (sidechain_number, ctip) = entry.into_diagnostic()?;
+        let ctip = ctip.value();
+        data.push(sidechain_number.value());
+        data.extend(ctip.outpoint.txid.to_byte_array());
+        data.extend(ctip.outpoint.vout.to_be_bytes());
+        data.extend(ctip.value.to_be_bytes());
+    }
+
+    let previous_votes = write_txn
+        .open_table(PREVIOUS_VOTES)
+        .into_diagnostic()?
+        .get(())
+        .into_diagnostic()?
+        .map(|previous_votes| previous_votes.value())
+        .unwrap_or_default();
+    data.extend((previous_votes.len() as u32).to_be_bytes());
+    for vote in previous_votes {
+        match vote {
+            Vote::Abstain => data.push(0),
+            Vote::Alarm => data.push(1),
+            Vote::Upvote { bundle_txid } => {
+                data.push(2);
+                data.extend(bundle_txid);
+            }
+        }
+    }
+
+    Ok(sha256d(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::{CoinbaseMessage, M4AckBundles, ALARM_ONE_BYTE};
+    use miette::Result;
+
+    use crate::bip300::sha256d;
+    use crate::test_utils::{TestChain, START_HEIGHT};
+    use crate::types::Hash256;
+
+    fn state_commitment(chain: &TestChain, height: u32) -> Result<Hash256> {
+        let block_info = chain.bip300.get_block_info(height)?.unwrap();
+        Ok(block_info.state_commitment)
+    }
+
+    #[test]
+    fn previous_votes_are_committed() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        // Alarms on a sidechain without bundles only change the previous votes.
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: vec![ALARM_ONE_BYTE],
+            })],
+            vec![],
+        )?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: vec![],
+            })],
+            vec![],
+        )?;
+        chain.connect(vec![], vec![])?;
+        let alarmed = state_commitment(&chain, START_HEIGHT)?;
+        let abstained = state_commitment(&chain, START_HEIGHT + 1)?;
+        assert_ne!(alarmed, abstained);
+        assert_eq!(state_commitment(&chain, START_HEIGHT + 2)?, abstained);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_state_commits_to_empty_sections() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[])?;
+        chain.connect(vec![], vec![])?;
+        // Five sections, each starting with a zero count.
+        assert_eq!(state_commitment(&chain, START_HEIGHT)?, sha256d(&[0; 20]));
+        Ok(())
+    }
+
+    #[test]
+    fn commitment_depends_only_on_the_state() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[])?;
+        chain.connect(vec![], vec![])?;
+        let unchanged = state_commitment(&chain, START_HEIGHT)?;
+        chain.connect(
+            vec![CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number: 1,
+                data: vec![1],
+            }],
+            vec![],
+        )?;
+        assert_ne!(state_commitment(&chain, START_HEIGHT + 1)?, unchanged);
+        // A different block leaving the same state behind commits to the same state.
+        chain.disconnect()?;
+        chain.connect(vec![], vec![])?;
+        assert_eq!(state_commitment(&chain, START_HEIGHT + 1)?, unchanged);
+        chain.bip300.check_consistency()?;
+        Ok(())
+    }
+}
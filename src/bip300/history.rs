@@ -0,0 +1,843 @@
+use super::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+// Every table below maps (key, height) to the value the key had after the block at that height
+// was connected, None meaning the key was removed. Only heights at which the key changed are
+// stored.
+
+pub(super) const SIDECHAIN_PROPOSAL_HISTORY: TableDefinition<
+    (&Hash256, u32),
+    Option<SidechainProposal>,
+> = TableDefinition::new("sidechain_proposal_history");
+
+pub(super) const SIDECHAIN_HISTORY: TableDefinition<(u8, u32), Option<Sidechain>> =
+    TableDefinition::new("sidechain_history");
+
+/// Keyed by (sidechain number, bundle index, height).
+pub(super) const BUNDLE_HISTORY: TableDefinition<(u8, u32, u32), Option<Bundle>> =
+    TableDefinition::new("bundle_history");
+
+pub(super) const CTIP_HISTORY: TableDefinition<(u8, u32), Option<Ctip>> =
+    TableDefinition::new("ctip_history");
+
+/// Keyed by height. Previous votes are a single value, so unlike the tables above an entry is
+/// stored whenever they differ from the latest entry, rather than for keys named by events.
+pub(super) const PREVIOUS_VOTES_HISTORY: TableDefinition<u32, Option<Vec<Vote>>> =
+    TableDefinition::new("previous_votes_history");
+
+/// Lowest height that history can still be queried at.
+pub(super) const HISTORY_PRUNED_HEIGHT: TableDefinition<(), u32> =
+    TableDefinition::new("history_pruned_height");
+
+/// How often, in blocks, history older than the configured depth is pruned.
+const HISTORY_PRUNE_INTERVAL: u32 = 144;
+
+/// State keys changed by a block.
+///
+/// Every state change made by `apply_block` is reported as an event, so the keys are derived
+/// from the block's events.
+#[derive(Default)]
+struct TouchedKeys {
+    sidechain_proposals: BTreeSet<Hash256>,
+    sidechains: BTreeSet<u8>,
+    bundles: BTreeSet<(u8, Hash256)>,
+    /// Number of bundles paid out per sidechain.
+    withdrawals: BTreeMap<u8, u32>,
+    ctips: BTreeSet<u8>,
+}
+
+impl TouchedKeys {
+    fn new(events: &[Event]) -> Self {
+        let mut touched = Self::default();
+        for event in events {
+            match event {
+                Event::SidechainProposalCreated { data_hash, .. }
+                | Event::SidechainProposalVoted { data_hash, .. }
+                | Event::SidechainProposalExpired { data_hash, .. } => {
+                    touched.sidechain_proposals.insert(*data_hash);
+                }
+                Event::SidechainActivated {
+                    sidechain_number,
+                    data_hash,
+                } => {
+                    touched.sidechain_proposals.insert(*data_hash);
+                    touched.sidechains.insert(*sidechain_number);
+                }
+                Event::BundleProposed {
+                    sidechain_number,
+                    bundle_txid,
+                }
+                | Event::BundleVoted {
+                    sidechain_number,
+                    bundle_txid,
+                    ..
+                } => {
+                    touched.bundles.insert((*sidechain_number, *bundle_txid));
+                }
+                Event::Deposit {
+                    sidechain_number, ..
+                } => {
+                    touched.ctips.insert(*sidechain_number);
+                }
+                Event::Withdrawal {
+                    sidechain_number, ..
+                } => {
+                    *touched.withdrawals.entry(*sidechain_number).or_default() += 1;
+                    touched.ctips.insert(*sidechain_number);
+                }
+                Event::BundleApproved { .. }
+                | Event::BlockConnected { .. }
+                | Event::BlockDisconnected { .. } => {}
+            }
+        }
+        touched
+    }
+
+    /// Bundle rows, as (sidechain number, bundle index), changed by the block.
+    ///
+    /// A withdrawal moves every bundle after the one paid out, so all rows of its sidechain are
+    /// included, up to the ones that are now empty.
+    fn bundle_rows(&self, write_txn: &WriteTransaction) -> Result<BTreeSet<(u8, u32)>> {
+        let bundle_index_to_bundle = write_txn
+            .open_table(BUNDLE_INDEX_TO_BUNDLE)
+            .into_diagnostic()?;
+        let bundle_txid_to_bundle_index = write_txn
+            .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+            .into_diagnostic()?;
+        let mut bundle_rows = BTreeSet::new();
+        for (sidechain_number, bundle_txid) in &self.bundles {
+            if self.withdrawals.contains_key(sidechain_number) {
+                continue;
+            }
+            let bundle_index = bundle_txid_to_bundle_index
+                .get((*sidechain_number, bundle_txid))
+                .into_diagnostic()?;
+            if let Some(bundle_index) = bundle_index {
+                bundle_rows.insert((*sidechain_number, bundle_index.value()));
+            }
+        }
+        for (&sidechain_number, &withdrawals) in &self.withdrawals {
+            let bundle_count =
+                bundles::next_bundle_index(&bundle_index_to_bundle, sidechain_number)?;
+            for bundle_index in 0..bundle_count + withdrawals {
+                bundle_rows.insert((sidechain_number, bundle_index));
+            }
+        }
+        Ok(bundle_rows)
+    }
+}
+
+/// Given history entries as (key, height, has_value), sorted by key and height, returns the ones
+/// below `below_height` that aren't needed to answer queries at or above it.
+fn stale_history_entries<K: Copy + PartialEq>(
+    entries: Vec<(K, u32, bool)>,
+    below_height: u32,
+) -> Vec<(K, u32)> {
+    let mut stale = vec![];
+    // The latest entry below `below_height` for the current key.
+    let mut pending: Option<(K, u32, bool)> = None;
+    for (key, height, has_value) in entries {
+        if let Some((pending_key, pending_height, pending_has_value)) = pending.take() {
+            let superseded = pending_key == key && height < below_height;
+            // A removal that isn't superseded is equivalent to having no entry at all.
+            if superseded || !pending_has_value {
+                stale.push((pending_key, pending_height));
+            }
+        }
+        if height < below_height {
+            pending = Some((key, height, has_value));
+        }
+    }
+    if let Some((pending_key, pending_height, false)) = pending {
+        stale.push((pending_key, pending_height));
+    }
+    stale
+}
+
+impl Bip300 {
+    pub(super) fn record_history(
+        &self,
+        write_txn: &WriteTransaction,
+        height: u32,
+        events: &[Event],
+    ) -> Result<()> {
+        let touched = TouchedKeys::new(events);
+        {
+            let data_hash_to_sidechain_proposal = write_txn
+                .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+                .into_diagnostic()?;
+            let mut sidechain_proposal_history = write_txn
+                .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+                .into_diagnostic()?;
+            for data_hash in &touched.sidechain_proposals {
+                let sidechain_proposal = data_hash_to_sidechain_proposal
+                    .get(data_hash)
+                    .into_diagnostic()?
+                    .map(|sidechain_proposal| sidechain_proposal.value());
+                sidechain_proposal_history
+                    .insert((data_hash, height), sidechain_proposal)
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let sidechain_number_to_sidechain = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            let mut sidechain_history =
+                write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            for &sidechain_number in &touched.sidechains {
+                let sidechain = sidechain_number_to_sidechain
+                    .get(sidechain_number)
+                    .into_diagnostic()?
+                    .map(|sidechain| sidechain.value());
+                sidechain_history
+                    .insert((sidechain_number, height), sidechain)
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let bundle_rows = touched.bundle_rows(write_txn)?;
+            let bundle_index_to_bundle = write_txn
+                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                .into_diagnostic()?;
+            let mut bundle_history = write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+            for (sidechain_number, bundle_index) in bundle_rows {
+                let bundle = bundle_index_to_bundle
+                    .get((sidechain_number, bundle_index))
+                    .into_diagnostic()?
+                    .map(|bundle| bundle.value());
+                bundle_history
+                    .insert((sidechain_number, bundle_index, height), bundle)
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let sidechain_number_to_ctip = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?;
+            let mut ctip_history = write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            for &sidechain_number in &touched.ctips {
+                let ctip = sidechain_number_to_ctip
+                    .get(sidechain_number)
+                    .into_diagnostic()?
+                    .map(|ctip| ctip.value());
+                ctip_history
+                    .insert((sidechain_number, height), ctip)
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let previous_votes = write_txn
+                .open_table(PREVIOUS_VOTES)
+                .into_diagnostic()?
+                .get(())
+                .into_diagnostic()?
+                .map(|previous_votes| previous_votes.value());
+            let mut previous_votes_history = write_txn
+                .open_table(PREVIOUS_VOTES_HISTORY)
+                .into_diagnostic()?;
+            let latest = previous_votes_history
+                .last()
+                .into_diagnostic()?
+                .and_then(|(_, previous_votes)| previous_votes.value());
+            if previous_votes != latest {
+                previous_votes_history
+                    .insert(height, previous_votes)
+                    .into_diagnostic()?;
+            }
+        }
+        if let Some(history_depth) = self.history_depth {
+            if height % HISTORY_PRUNE_INTERVAL == 0 && height > history_depth {
+                self.prune_history(write_txn, height - history_depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes the state changes made by the block at `height`, restoring every key it touched to
+    /// its previous version.
+    pub(super) fn revert_history(
+        &self,
+        write_txn: &WriteTransaction,
+        height: u32,
+        events: &[Event],
+    ) -> Result<()> {
+        let touched = TouchedKeys::new(events);
+        {
+            let mut data_hash_to_sidechain_proposal = write_txn
+                .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+                .into_diagnostic()?;
+            let mut sidechain_proposal_history = write_txn
+                .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+                .into_diagnostic()?;
+            for data_hash in &touched.sidechain_proposals {
+                sidechain_proposal_history
+                    .remove((data_hash, height))
+                    .into_diagnostic()?;
+                let previous = sidechain_proposal_history
+                    .range((data_hash, 0)..(data_hash, height))
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, sidechain_proposal)| sidechain_proposal.value());
+                if let Some(previous) = previous {
+                    data_hash_to_sidechain_proposal
+                        .insert(data_hash, previous)
+                        .into_diagnostic()?;
+                } else {
+                    data_hash_to_sidechain_proposal
+                        .remove(data_hash)
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        {
+            let mut sidechain_number_to_sidechain = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            let mut sidechain_history =
+                write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            for &sidechain_number in &touched.sidechains {
+                sidechain_history
+                    .remove((sidechain_number, height))
+                    .into_diagnostic()?;
+                let previous = sidechain_history
+                    .range((sidechain_number, 0)..(sidechain_number, height))
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, sidechain)| sidechain.value());
+                if let Some(previous) = previous {
+                    sidechain_number_to_sidechain
+                        .insert(sidechain_number, previous)
+                        .into_diagnostic()?;
+                } else {
+                    sidechain_number_to_sidechain
+                        .remove(sidechain_number)
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        {
+            // Rows are restored in ascending order, so a bundle moved back up by a reverted
+            // withdrawal is indexed at its old row after its new row was cleared.
+            let bundle_rows = touched.bundle_rows(write_txn)?;
+            let mut bundle_index_to_bundle = write_txn
+                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                .into_diagnostic()?;
+            let mut bundle_txid_to_bundle_index = write_txn
+                .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+                .into_diagnostic()?;
+            let mut bundle_history = write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+            for (sidechain_number, bundle_index) in bundle_rows {
+                bundle_history
+                    .remove((sidechain_number, bundle_index, height))
+                    .into_diagnostic()?;
+                let previous = bundle_history
+                    .range(
+                        (sidechain_number, bundle_index, 0)
+                            ..(sidechain_number, bundle_index, height),
+                    )
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, bundle)| bundle.value());
+                let current = bundle_index_to_bundle
+                    .remove((sidechain_number, bundle_index))
+                    .into_diagnostic()?
+                    .map(|bundle| bundle.value());
+                if let Some(current) = current {
+                    bundle_txid_to_bundle_index
+                        .remove((sidechain_number, &current.bundle_txid))
+                        .into_diagnostic()?;
+                }
+                if let Some(previous) = previous {
+                    bundle_index_to_bundle
+                        .insert((sidechain_number, bundle_index), &previous)
+                        .into_diagnostic()?;
+                    bundle_txid_to_bundle_index
+                        .insert((sidechain_number, &previous.bundle_txid), bundle_index)
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        {
+            let mut sidechain_number_to_ctip = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?;
+            let mut ctip_history = write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            for &sidechain_number in &touched.ctips {
+                ctip_history
+                    .remove((sidechain_number, height))
+                    .into_diagnostic()?;
+                let previous = ctip_history
+                    .range((sidechain_number, 0)..(sidechain_number, height))
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, ctip)| ctip.value());
+                if let Some(previous) = previous {
+                    sidechain_number_to_ctip
+                        .insert(sidechain_number, previous)
+                        .into_diagnostic()?;
+                } else {
+                    sidechain_number_to_ctip
+                        .remove(sidechain_number)
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        {
+            let mut previous_votes_history = write_txn
+                .open_table(PREVIOUS_VOTES_HISTORY)
+                .into_diagnostic()?;
+            let changed = previous_votes_history
+                .remove(height)
+                .into_diagnostic()?
+                .is_some();
+            if changed {
+                let previous = previous_votes_history
+                    .range(..height)
+                    .into_diagnostic()?
+                    .next_back()
+                    .transpose()
+                    .into_diagnostic()?
+                    .and_then(|(_, previous_votes)| previous_votes.value());
+                let mut previous_votes = write_txn.open_table(PREVIOUS_VOTES).into_diagnostic()?;
+                if let Some(previous) = previous {
+                    previous_votes.insert((), previous).into_diagnostic()?;
+                } else {
+                    previous_votes.remove(()).into_diagnostic()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops history that is only needed to answer queries below `below_height`, along with the
+    /// events of those blocks. Blocks below `below_height` can no longer be disconnected.
+    fn prune_history(&self, write_txn: &WriteTransaction, below_height: u32) -> Result<()> {
+        {
+            let mut history_pruned_height = write_txn
+                .open_table(HISTORY_PRUNED_HEIGHT)
+                .into_diagnostic()?;
+            let pruned_height = history_pruned_height
+                .get(())
+                .into_diagnostic()?
+                .map(|pruned_height| pruned_height.value())
+                .unwrap_or(0);
+            if below_height <= pruned_height {
+                return Ok(());
+            }
+            history_pruned_height
+                .insert((), below_height)
+                .into_diagnostic()?;
+        }
+        // Only entries below `below_height` are read. Tables keyed by (key, height) are read a
+        // key at a time, skipping from the last height of one key to the next key.
+        {
+            let mut sidechain_proposal_history = write_txn
+                .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+                .into_diagnostic()?;
+            let mut entries = vec![];
+            let mut next_data_hash = sidechain_proposal_history
+                .first()
+                .into_diagnostic()?
+                .map(|(key, _)| *key.value().0);
+            while let Some(data_hash) = next_data_hash {
+                for entry in sidechain_proposal_history
+                    .range((&data_hash, 0)..(&data_hash, below_height))
+                    .into_diagnostic()?
+                {
+                    let (key, sidechain_proposal) = entry.into_diagnostic()?;
+                    let (_, height) = key.value();
+                    entries.push((data_hash, height, sidechain_proposal.value().is_some()));
+                }
+                next_data_hash = sidechain_proposal_history
+                    .range((Bound::Excluded((&data_hash, u32::MAX)), Bound::Unbounded))
+                    .into_diagnostic()?
+                    .next()
+                    .transpose()
+                    .into_diagnostic()?
+                    .map(|(key, _)| *key.value().0);
+            }
+            for (data_hash, height) in stale_history_entries(entries, below_height) {
+                sidechain_proposal_history
+                    .remove((&data_hash, height))
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let mut sidechain_history =
+                write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            let mut entries = vec![];
+            for sidechain_number in 0..=u8::MAX {
+                for entry in sidechain_history
+                    .range((sidechain_number, 0)..(sidechain_number, below_height))
+                    .into_diagnostic()?
+                {
+                    let (key, sidechain) = entry.into_diagnostic()?;
+                    let (_, height) = key.value();
+                    entries.push((sidechain_number, height, sidechain.value().is_some()));
+                }
+            }
+            for key in stale_history_entries(entries, below_height) {
+                sidechain_history.remove(key).into_diagnostic()?;
+            }
+        }
+        {
+            let mut bundle_history = write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+            let mut entries = vec![];
+            let mut next_bundle = bundle_history.first().into_diagnostic()?.map(|(key, _)| {
+                let (sidechain_number, bundle_index, _) = key.value();
+                (sidechain_number, bundle_index)
+            });
+            while let Some((sidechain_number, bundle_index)) = next_bundle {
+                for entry in bundle_history
+                    .range(
+                        (sidechain_number, bundle_index, 0)
+                            ..(sidechain_number, bundle_index, below_height),
+                    )
+                    .into_diagnostic()?
+                {
+                    let (key, bundle) = entry.into_diagnostic()?;
+                    let (_, _, height) = key.value();
+                    entries.push((
+                        (sidechain_number, bundle_index),
+                        height,
+                        bundle.value().is_some(),
+                    ));
+                }
+                next_bundle = bundle_history
+                    .range((
+                        Bound::Excluded((sidechain_number, bundle_index, u32::MAX)),
+                        Bound::Unbounded,
+                    ))
+                    .into_diagnostic()?
+                    .next()
+                    .transpose()
+                    .into_diagnostic()?
+                    .map(|(key, _)| {
+                        let (sidechain_number, bundle_index, _) = key.value();
+                        (sidechain_number, bundle_index)
+                    });
+            }
+            for ((sidechain_number, bundle_index), height) in
+                stale_history_entries(entries, below_height)
+            {
+                bundle_history
+                    .remove((sidechain_number, bundle_index, height))
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let mut ctip_history = write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            let mut entries = vec![];
+            for sidechain_number in 0..=u8::MAX {
+                for entry in ctip_history
+                    .range((sidechain_number, 0)..(sidechain_number, below_height))
+                    .into_diagnostic()?
+                {
+                    let (key, ctip) = entry.into_diagnostic()?;
+                    let (_, height) = key.value();
+                    entries.push((sidechain_number, height, ctip.value().is_some()));
+                }
+            }
+            for key in stale_history_entries(entries, below_height) {
+                ctip_history.remove(key).into_diagnostic()?;
+            }
+        }
+        {
+            let mut previous_votes_history = write_txn
+                .open_table(PREVIOUS_VOTES_HISTORY)
+                .into_diagnostic()?;
+            let mut entries = vec![];
+            for entry in previous_votes_history
+                .range(..below_height)
+                .into_diagnostic()?
+            {
+                let (height, previous_votes) = entry.into_diagnostic()?;
+                entries.push(((), height.value(), previous_votes.value().is_some()));
+            }
+            for ((), height) in stale_history_entries(entries, below_height) {
+                previous_votes_history.remove(height).into_diagnostic()?;
+            }
+        }
+        {
+            let mut block_height_to_events = write_txn
+                .open_table(BLOCK_HEIGHT_TO_EVENTS)
+                .into_diagnostic()?;
+            block_height_to_events
+                .drain(..below_height)
+                .into_diagnostic()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that state can be queried at `height`.
+    pub(super) fn check_history_height(
+        &self,
+        read_txn: &ReadTransaction,
+        height: u32,
+    ) -> Result<()> {
+        let tip_height = read_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?
+            .last()
+            .into_diagnostic()?
+            .map(|(tip_height, _)| tip_height.value());
+        match tip_height {
+            Some(tip_height) if height <= tip_height => {}
+            _ => return Err(miette!("height {height} is above the chain tip")),
+        }
+        let pruned_height = read_txn
+            .open_table(HISTORY_PRUNED_HEIGHT)
+            .into_diagnostic()?
+            .get(())
+            .into_diagnostic()?
+            .map(|pruned_height| pruned_height.value())
+            .unwrap_or(0);
+        if height < pruned_height {
+            return Err(miette!(
+                "history below height {pruned_height} was pruned, can't query height {height}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bip300_messages::{sha256d, CoinbaseMessage, M4AckBundles};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Transaction};
+    use miette::{IntoDiagnostic, Result};
+    use redb::ReadableTable;
+
+    use super::{stale_history_entries, CTIP_HISTORY};
+    use crate::test_utils::{ctip, hash, sidechain, spend_ctip, TestChain, START_HEIGHT};
+    use crate::types::{Ctip, Event, InitialState};
+
+    fn propose_sidechain(sidechain_number: u8, data: u8) -> CoinbaseMessage {
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number,
+            data: vec![data],
+        }
+    }
+
+    /// The ctip `transaction` creates.
+    fn new_ctip(transaction: &Transaction) -> Ctip {
+        Ctip {
+            outpoint: OutPoint {
+                txid: transaction.txid(),
+                vout: 0,
+            },
+            value: transaction.output[0].value.to_sat(),
+        }
+    }
+
+    fn proposal_data(chain: &TestChain, at_height: Option<u32>) -> Result<Vec<Vec<u8>>> {
+        let proposals = chain.bip300.get_sidechain_proposals(at_height)?;
+        let mut data: Vec<_> = proposals
+            .into_iter()
+            .map(|(_, proposal)| proposal.data)
+            .collect();
+        data.sort();
+        Ok(data)
+    }
+
+    #[test]
+    fn state_can_be_queried_at_past_heights() -> Result<()> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+        })?;
+        chain.connect(vec![propose_sidechain(1, 1)], vec![])?;
+        let deposit = spend_ctip(0, &ctip(1, 1000), 1500);
+        chain.connect(vec![propose_sidechain(1, 2)], vec![deposit.clone()])?;
+        chain.connect(vec![], vec![])?;
+
+        assert_eq!(proposal_data(&chain, Some(START_HEIGHT))?, [vec![1]]);
+        assert_eq!(
+            proposal_data(&chain, Some(START_HEIGHT + 1))?,
+            [vec![1], vec![2]]
+        );
+        assert_eq!(
+            proposal_data(&chain, Some(START_HEIGHT + 2))?,
+            [vec![1], vec![2]]
+        );
+        assert!(chain
+            .bip300
+            .get_sidechain_proposals(Some(START_HEIGHT + 3))
+            .is_err());
+
+        assert_eq!(
+            chain.bip300.get_ctip(0, Some(START_HEIGHT))?,
+            Some(ctip(1, 1000))
+        );
+        assert_eq!(
+            chain.bip300.get_ctip(0, Some(START_HEIGHT + 1))?,
+            Some(new_ctip(&deposit))
+        );
+        assert_eq!(
+            chain.bip300.get_ctip(0, Some(START_HEIGHT + 2))?,
+            Some(new_ctip(&deposit))
+        );
+        assert_eq!(chain.bip300.get_ctip(0, None)?, Some(new_ctip(&deposit)));
+        assert!(chain.bip300.get_ctip(0, Some(START_HEIGHT + 3)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pruning_keeps_the_state_at_and_above_the_height() -> Result<()> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+        })?;
+        chain.connect(
+            vec![
+                propose_sidechain(1, 1),
+                CoinbaseMessage::M3ProposeBundle {
+                    sidechain_number: 0,
+                    bundle_txid: hash(1),
+                },
+            ],
+            vec![],
+        )?;
+        // The proposal, bundle and ctip change in each of the next three blocks.
+        let mut deposit_ctip = ctip(1, 1000);
+        for value in [1500, 2000, 2500] {
+            let votes = vec![
+                CoinbaseMessage::M2AckSidechain {
+                    sidechain_number: 1,
+                    data_hash: sha256d(&[1]),
+                },
+                CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte { upvotes: vec![0] }),
+            ];
+            let deposit = spend_ctip(0, &deposit_ctip, value);
+            chain.connect(votes, vec![deposit.clone()])?;
+            deposit_ctip = new_ctip(&deposit);
+        }
+        chain.connect(vec![], vec![])?;
+
+        let state = |height| -> Result<_> {
+            let proposals: Vec<_> = chain
+                .bip300
+                .get_sidechain_proposals(Some(height))?
+                .into_iter()
+                .map(|(data_hash, proposal)| (data_hash, proposal.vote_count))
+                .collect();
+            Ok((
+                proposals,
+                chain.bip300.get_bundles(0, Some(height))?,
+                chain.bip300.get_ctip(0, Some(height))?,
+            ))
+        };
+        let ctip_history_len = || -> Result<u64> {
+            let read_txn = chain.bip300.db.begin_read().into_diagnostic()?;
+            let ctip_history = read_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            ctip_history.len().into_diagnostic()
+        };
+        // The state at the tip is read from the entries of the block below it.
+        let before = state(START_HEIGHT + 4)?;
+        let ctip_history_len_before = ctip_history_len()?;
+
+        let write_txn = chain.bip300.db.begin_write().into_diagnostic()?;
+        chain.bip300.prune_history(&write_txn, START_HEIGHT + 4)?;
+        write_txn.commit().into_diagnostic()?;
+
+        assert_eq!(state(START_HEIGHT + 4)?, before);
+        assert!(ctip_history_len()? < ctip_history_len_before);
+        assert!(chain.bip300.get_ctip(0, Some(START_HEIGHT + 3)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deposits_are_reported_as_events() -> Result<()> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+        })?;
+        let deposit = spend_ctip(0, &ctip(1, 1000), 1500);
+        let block = chain.connect(vec![], vec![deposit.clone()])?;
+        let events = chain.bip300.get_events(START_HEIGHT)?;
+        assert_eq!(
+            events,
+            [(
+                START_HEIGHT,
+                vec![
+                    Event::Deposit {
+                        sidechain_number: 0,
+                        outpoint: new_ctip(&deposit).outpoint,
+                        value: 500,
+                        total_value: 1500,
+                    },
+                    Event::BlockConnected {
+                        block_hash: block.block_hash().to_byte_array(),
+                    },
+                ]
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn disconnecting_restores_the_previous_state() -> Result<()> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: BTreeMap::from([(0, ctip(1, 1000))]),
+        })?;
+        chain.connect(vec![propose_sidechain(1, 1)], vec![])?;
+        let tip = chain.bip300.get_chain_tip()?;
+        chain.connect(
+            vec![propose_sidechain(1, 2)],
+            vec![spend_ctip(0, &ctip(1, 1000), 1500)],
+        )?;
+
+        chain.disconnect()?;
+        assert_eq!(chain.bip300.get_chain_tip()?, tip);
+        assert_eq!(proposal_data(&chain, None)?, [vec![1]]);
+        assert_eq!(chain.bip300.get_ctip(0, None)?, Some(ctip(1, 1000)));
+        assert_eq!(chain.bip300.get_events(START_HEIGHT + 1)?, []);
+
+        chain.disconnect()?;
+        assert_eq!(chain.bip300.get_chain_tip()?, None);
+        assert_eq!(proposal_data(&chain, None)?, Vec::<Vec<u8>>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn disconnecting_requires_the_tip() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[])?;
+        let first = chain.connect(vec![], vec![])?;
+        chain.connect(vec![], vec![])?;
+        assert!(chain.bip300.disconnect_block(&first).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_entries_are_the_ones_superseded_below_the_height() {
+        let entries = vec![
+            // Changed twice below height 10, the latest of which is still needed.
+            ('a', 1, true),
+            ('a', 5, true),
+            ('a', 12, true),
+            // Removed below height 10, so no entry is needed.
+            ('b', 2, true),
+            ('b', 3, false),
+            // Only changed at and above height 10.
+            ('c', 10, true),
+        ];
+        assert_eq!(
+            stale_history_entries(entries, 10),
+            [('a', 1), ('b', 2), ('b', 3)]
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use super::*;
+use redb::{RedbValue, TypeName};
+use serde::{Deserialize, Serialize};
+
+/// Facts about the database itself, such as its schema version.
+const METADATA: TableDefinition<&str, u32> = TableDefinition::new("metadata");
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// A migration rewrites the tables of one schema version into the layout of the next.
+type Migration = fn(&WriteTransaction) -> Result<()>;
+
+/// `MIGRATIONS[i]` migrates a database from schema version `i + 1` to `i + 2`, so there must be
+/// `SCHEMA_VERSION - 1` of them.
+const MIGRATIONS: &[Migration] = &[migrate_bundles_to_rows, drop_legacy_previous_votes];
+
+const _: () = assert!(MIGRATIONS.len() == SCHEMA_VERSION as usize - 1);
+
+/// Schema version stored in the database.
+pub(super) fn stored_schema_version(read_txn: &ReadTransaction) -> Result<Option<u32>> {
+    let version = read_txn
+        .open_table(METADATA)
+        .into_diagnostic()?
+        .get(SCHEMA_VERSION_KEY)
+        .into_diagnostic()?
+        .map(|version| version.value());
+    Ok(version)
+}
+
+/// Brings the database up to `SCHEMA_VERSION`, refusing to open databases written by newer
+/// versions.
+pub(super) fn migrate(db: &Database) -> Result<()> {
+    let write_txn = db.begin_write().into_diagnostic()?;
+    let is_new = write_txn.list_tables().into_diagnostic()?.next().is_none();
+    let stored_version = write_txn
+        .open_table(METADATA)
+        .into_diagnostic()?
+        .get(SCHEMA_VERSION_KEY)
+        .into_diagnostic()?
+        .map(|version| version.value());
+    // Databases written before the schema was versioned are at version 1.
+    let version = match stored_version {
+        Some(version) => version,
+        None if is_new => SCHEMA_VERSION,
+        None => 1,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(miette!(
+            "database schema version {version} is newer than the supported version \
+             {SCHEMA_VERSION}, upgrade bip300_monitor to open it"
+        ));
+    }
+    if version == 0 {
+        return Err(miette!("invalid database schema version 0"));
+    }
+    for (from_version, migration) in (version..).zip(&MIGRATIONS[version as usize - 1..]) {
+        info!(
+            from_version,
+            to_version = from_version + 1,
+            "migrating database schema"
+        );
+        migration(&write_txn)?;
+    }
+    if stored_version != Some(SCHEMA_VERSION) {
+        write_txn
+            .open_table(METADATA)
+            .into_diagnostic()?
+            .insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION)
+            .into_diagnostic()?;
+    }
+    write_txn.commit().into_diagnostic()?;
+    Ok(())
+}
+
+/// `Bundle` as stored up to schema version 1, encoded with bincode.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct LegacyBundle {
+    bundle_txid: Hash256,
+    vote_count: u16,
+}
+
+impl RedbValue for LegacyBundle {
+    type SelfType<'a> = LegacyBundle;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn type_name() -> TypeName {
+        TypeName::new("Bundle")
+    }
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).unwrap()
+    }
+}
+
+impl From<&LegacyBundle> for Bundle {
+    fn from(bundle: &LegacyBundle) -> Self {
+        Self {
+            bundle_txid: bundle.bundle_txid,
+            vote_count: bundle.vote_count,
+        }
+    }
+}
+
+const LEGACY_SIDECHAIN_NUMBER_TO_BUNDLES: TableDefinition<u8, Vec<LegacyBundle>> =
+    TableDefinition::new("sidechain_number_to_bundles");
+
+const LEGACY_BUNDLES_HISTORY: TableDefinition<(u8, u32), Option<Vec<LegacyBundle>>> =
+    TableDefinition::new("bundles_history");
+
+/// Version 2 stores one row per bundle, plus an index by bundle txid, instead of a list of
+/// bundles per sidechain.
+fn migrate_bundles_to_rows(write_txn: &WriteTransaction) -> Result<()> {
+    let mut legacy_bundles = vec![];
+    for entry in write_txn
+        .open_table(LEGACY_SIDECHAIN_NUMBER_TO_BUNDLES)
+        .into_diagnostic()?
+        .iter()
+        .into_diagnostic()?
+    {
+        let (sidechain_number, bundles) = entry.into_diagnostic()?;
+        legacy_bundles.push((sidechain_number.value(), bundles.value()));
+    }
+    let mut legacy_history = vec![];
+    for entry in write_txn
+        .open_table(LEGACY_BUNDLES_HISTORY)
+        .into_diagnostic()?
+        .iter()
+        .into_diagnostic()?
+    {
+        let (key, bundles) = entry.into_diagnostic()?;
+        legacy_history.push((key.value(), bundles.value().unwrap_or_default()));
+    }
+    write_txn
+        .delete_table(LEGACY_SIDECHAIN_NUMBER_TO_BUNDLES)
+        .into_diagnostic()?;
+    write_txn
+        .delete_table(LEGACY_BUNDLES_HISTORY)
+        .into_diagnostic()?;
+
+    let mut bundle_index_to_bundle = write_txn
+        .open_table(BUNDLE_INDEX_TO_BUNDLE)
+        .into_diagnostic()?;
+    let mut bundle_txid_to_bundle_index = write_txn
+        .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+        .into_diagnostic()?;
+    for (sidechain_number, bundles) in legacy_bundles {
+        for (bundle_index, bundle) in (0..).zip(&bundles) {
+            bundle_index_to_bundle
+                .insert((sidechain_number, bundle_index), Bundle::from(bundle))
+                .into_diagnostic()?;
+            bundle_txid_to_bundle_index
+                .insert((sidechain_number, &bundle.bundle_txid), bundle_index)
+                .into_diagnostic()?;
+        }
+    }
+
+    // Legacy history holds the full list after every change, so only the rows that differ from
+    // the previous list are kept.
+    let mut bundle_history = write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+    let mut previous: Option<(u8, Vec<LegacyBundle>)> = None;
+    for ((sidechain_number, height), bundles) in legacy_history {
+        let previous_bundles = match previous {
+            Some((previous_sidechain_number, previous_bundles))
+                if previous_sidechain_number == sidechain_number =>
+            {
+                previous_bundles
+            }
+            _ => vec![],
+        };
+        for bundle_index in 0..previous_bundles.len().max(bundles.len()) {
+            let bundle = bundles.get(bundle_index);
+            if previous_bundles.get(bundle_index) != bundle {
+                bundle_history
+                    .insert(
+                        (sidechain_number, bundle_index as u32, height),
+                        bundle.map(Bundle::from),
+                    )
+                    .into_diagnostic()?;
+            }
+        }
+        previous = Some((sidechain_number, bundles));
+    }
+    Ok(())
+}
+
+const LEGACY_PREVIOUS_VOTES: TableDefinition<(), Vec<&Hash256>> =
+    TableDefinition::new("previous_vote_vector");
+
+/// Version 3 keeps the votes of the last M4 per sidechain. Up to version 2 the table was never
+/// written to, so there is nothing to carry over, but M4s connected before the migration aren't
+/// repeated by a RepeatPrevious after it.
+fn drop_legacy_previous_votes(write_txn: &WriteTransaction) -> Result<()> {
+    write_txn
+        .delete_table(LEGACY_PREVIOUS_VOTES)
+        .into_diagnostic()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redb::TableHandle;
+
+    fn table_names(db: &Database) -> Result<Vec<String>> {
+        let read_txn = db.begin_read().into_diagnostic()?;
+        let names = read_txn
+            .list_tables()
+            .into_diagnostic()?
+            .map(|table| table.name().to_owned())
+            .collect();
+        Ok(names)
+    }
+
+    fn create_database(dir: &tempfile::TempDir) -> Result<Database> {
+        Database::create(dir.path().join("bip300.redb")).into_diagnostic()
+    }
+
+    fn set_schema_version(db: &Database, version: u32) -> Result<()> {
+        let write_txn = db.begin_write().into_diagnostic()?;
+        write_txn
+            .open_table(METADATA)
+            .into_diagnostic()?
+            .insert(SCHEMA_VERSION_KEY, version)
+            .into_diagnostic()?;
+        write_txn.commit().into_diagnostic()
+    }
+
+    fn schema_version(db: &Database) -> Result<Option<u32>> {
+        stored_schema_version(&db.begin_read().into_diagnostic()?)
+    }
+
+    #[test]
+    fn new_databases_start_at_the_current_version() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let db = create_database(&dir)?;
+        migrate(&db)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION));
+        // Migrating again is a no-op.
+        migrate(&db)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn newer_databases_are_refused() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let db = create_database(&dir)?;
+        set_schema_version(&db, SCHEMA_VERSION + 1)?;
+        assert!(migrate(&db).is_err());
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION + 1));
+        Ok(())
+    }
+
+    #[test]
+    fn version_2_drops_the_legacy_previous_votes() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let db = create_database(&dir)?;
+        set_schema_version(&db, 2)?;
+        let write_txn = db.begin_write().into_diagnostic()?;
+        write_txn
+            .open_table(LEGACY_PREVIOUS_VOTES)
+            .into_diagnostic()?
+            .insert((), vec![&[1; 32]])
+            .into_diagnostic()?;
+        write_txn.commit().into_diagnostic()?;
+
+        migrate(&db)?;
+        assert_eq!(schema_version(&db)?, Some(3));
+        assert!(!table_names(&db)?.contains(&"previous_vote_vector".to_owned()));
+        Ok(())
+    }
+
+    fn legacy_bundle(n: u8, vote_count: u16) -> LegacyBundle {
+        LegacyBundle {
+            bundle_txid: [n; 32],
+            vote_count,
+        }
+    }
+
+    fn bundle(n: u8, vote_count: u16) -> Bundle {
+        Bundle::from(&legacy_bundle(n, vote_count))
+    }
+
+    #[test]
+    fn version_1_bundle_lists_become_rows() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let db = create_database(&dir)?;
+        // Unversioned, as written before the schema was versioned.
+        let write_txn = db.begin_write().into_diagnostic()?;
+        write_txn
+            .open_table(LEGACY_SIDECHAIN_NUMBER_TO_BUNDLES)
+            .into_diagnostic()?
+            .insert(0, vec![legacy_bundle(2, 1)])
+            .into_diagnostic()?;
+        {
+            let mut legacy_history = write_txn
+                .open_table(LEGACY_BUNDLES_HISTORY)
+                .into_diagnostic()?;
+            let lists = [
+                (5, vec![legacy_bundle(1, 0)]),
+                (6, vec![legacy_bundle(1, 0), legacy_bundle(2, 0)]),
+                (7, vec![legacy_bundle(1, 3), legacy_bundle(2, 1)]),
+                (8, vec![legacy_bundle(2, 1)]),
+            ];
+            for (height, bundles) in lists {
+                legacy_history
+                    .insert((0, height), Some(bundles))
+                    .into_diagnostic()?;
+            }
+        }
+        write_txn.commit().into_diagnostic()?;
+
+        migrate(&db)?;
+        assert_eq!(schema_version(&db)?, Some(SCHEMA_VERSION));
+        let table_names = table_names(&db)?;
+        assert!(!table_names.contains(&"sidechain_number_to_bundles".to_owned()));
+        assert!(!table_names.contains(&"bundles_history".to_owned()));
+        let read_txn = db.begin_read().into_diagnostic()?;
+        let mut rows = vec![];
+        for entry in read_txn
+            .open_table(BUNDLE_INDEX_TO_BUNDLE)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (key, bundle) = entry.into_diagnostic()?;
+            rows.push((key.value(), bundle.value()));
+        }
+        assert_eq!(rows, [((0, 0), bundle(2, 1))]);
+        let bundle_index = read_txn
+            .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+            .into_diagnostic()?
+            .get((0, &[2; 32]))
+            .into_diagnostic()?
+            .map(|bundle_index| bundle_index.value());
+        assert_eq!(bundle_index, Some(0));
+        let mut history = vec![];
+        for entry in read_txn
+            .open_table(BUNDLE_HISTORY)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (key, bundle) = entry.into_diagnostic()?;
+            history.push((key.value(), bundle.value()));
+        }
+        // Only rows that changed are kept, and rows that emptied are removals.
+        assert_eq!(
+            history,
+            [
+                ((0, 0, 5), Some(bundle(1, 0))),
+                ((0, 0, 7), Some(bundle(1, 3))),
+                ((0, 0, 8), Some(bundle(2, 1))),
+                ((0, 1, 6), Some(bundle(2, 0))),
+                ((0, 1, 7), Some(bundle(2, 1))),
+                ((0, 1, 8), None),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+use super::*;
+use bincode::Options;
+use redb::{RedbKey, RedbValue};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"BIP300SS";
+
+/// Version of the snapshot format, bumped whenever `Snapshot` changes.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// The full BIP300 state at a chain tip.
+///
+/// Files start with `SNAPSHOT_MAGIC` and the little endian `SNAPSHOT_VERSION`, followed by the
+/// bincode encoded snapshot.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    network: Network,
+    consensus_params: ConsensusParams,
+    start_block: StartBlock,
+    tip_height: u32,
+    tip: BlockInfo,
+    sidechain_proposals: Vec<(Hash256, SidechainProposal)>,
+    sidechains: Vec<Sidechain>,
+    bundles: Vec<(u8, Vec<Bundle>)>,
+    ctips: Vec<(u8, Ctip)>,
+    previous_votes: Vec<Vote>,
+}
+
+impl Bip300 {
+    /// Writes the state at the chain tip to a snapshot file at `path`.
+    pub fn export_snapshot(&self, path: &Path) -> Result<u32> {
+        let read_txn = self.db.begin_read().into_diagnostic()?;
+        let Some((tip_height, tip)) = read_txn
+            .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+            .into_diagnostic()?
+            .last()
+            .into_diagnostic()?
+            .map(|(tip_height, tip)| (tip_height.value(), tip.value()))
+        else {
+            return Err(miette!("there are no blocks to snapshot"));
+        };
+        let mut snapshot = Snapshot {
+            network: self.network,
+            consensus_params: self.consensus_params,
+            start_block: self.start_block,
+            tip_height,
+            tip,
+            sidechain_proposals: vec![],
+            sidechains: vec![],
+            bundles: vec![],
+            ctips: vec![],
+            previous_votes: vec![],
+        };
+        for entry in read_txn
+            .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (data_hash, sidechain_proposal) = entry.into_diagnostic()?;
+            snapshot
+                .sidechain_proposals
+                .push((*data_hash.value(), sidechain_proposal.value()));
+        }
+        for entry in read_txn
+            .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (_, sidechain) = entry.into_diagnostic()?;
+            snapshot.sidechains.push(sidechain.value());
+        }
+        for entry in read_txn
+            .open_table(BUNDLE_INDEX_TO_BUNDLE)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (key, bundle) = entry.into_diagnostic()?;
+            let (sidechain_number, _) = key.value();
+            match snapshot.bundles.last_mut() {
+                Some((last_sidechain_number, bundles))
+                    if *last_sidechain_number == sidechain_number =>
+                {
+                    bundles.push(bundle.value())
+                }
+                _ => snapshot
+                    .bundles
+                    .push((sidechain_number, vec![bundle.value()])),
+            }
+        }
+        for entry in read_txn
+            .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+            .into_diagnostic()?
+            .iter()
+            .into_diagnostic()?
+        {
+            let (sidechain_number, ctip) = entry.into_diagnostic()?;
+            snapshot
+                .ctips
+                .push((sidechain_number.value(), ctip.value()));
+        }
+        if let Some(previous_votes) = read_txn
+            .open_table(PREVIOUS_VOTES)
+            .into_diagnostic()?
+            .get(())
+            .into_diagnostic()?
+        {
+            snapshot.previous_votes = previous_votes.value();
+        }
+
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend(SNAPSHOT_VERSION.to_le_bytes());
+        data.extend(bincode::serialize(&snapshot).into_diagnostic()?);
+        fs::write(path, data).into_diagnostic()?;
+        Ok(tip_height)
+    }
+
+    /// Restores the state from the snapshot file at `path` into a database without any state, so
+    /// no blocks were connected to it and no state was seeded.
+    ///
+    /// The restored tip can't be disconnected, and history can only be queried from it onwards.
+    pub fn import_snapshot(&self, path: &Path) -> Result<u32> {
+        let data = fs::read(path).into_diagnostic()?;
+        let Some(data) = data.strip_prefix(&SNAPSHOT_MAGIC) else {
+            return Err(miette!("{} is not a snapshot", path.display()));
+        };
+        if data.len() < 4 {
+            return Err(miette!("{} is truncated", path.display()));
+        }
+        let version = u32::from_le_bytes(data[..4].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(miette!(
+                "snapshot version {version} is not supported, expected {SNAPSHOT_VERSION}"
+            ));
+        }
+        // Encoded like `bincode::serialize`, and the length prefixes can't claim more data than
+        // the file holds.
+        let snapshot: Snapshot = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(data.len() as u64)
+            .deserialize(&data[4..])
+            .into_diagnostic()?;
+        if snapshot.network != self.network {
+            return Err(miette!(
+                "snapshot is for {}, not {}",
+                snapshot.network,
+                self.network
+            ));
+        }
+        if snapshot.consensus_params != self.consensus_params {
+            return Err(miette!(
+                "snapshot was built with consensus params {:?}, expected {:?}",
+                snapshot.consensus_params,
+                self.consensus_params
+            ));
+        }
+        if snapshot.start_block != self.start_block {
+            return Err(miette!(
+                "snapshot starts at {:?}, expected {:?}",
+                snapshot.start_block,
+                self.start_block
+            ));
+        }
+
+        let write_txn = self.db.begin_write().into_diagnostic()?;
+        let tip_height = snapshot.tip_height;
+        if !is_state_empty(&write_txn)? {
+            return Err(miette!(
+                "can't import a snapshot into a database that already has state"
+            ));
+        }
+        {
+            let mut block_height_to_block_info = write_txn
+                .open_table(BLOCK_HEIGHT_TO_BLOCK_INFO)
+                .into_diagnostic()?;
+            block_height_to_block_info
+                .insert(tip_height, &snapshot.tip)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(HISTORY_PRUNED_HEIGHT)
+                .into_diagnostic()?
+                .insert((), tip_height)
+                .into_diagnostic()?;
+        }
+        {
+            let mut data_hash_to_sidechain_proposal = write_txn
+                .open_table(DATA_HASH_TO_SIDECHAIN_PROPOSAL)
+                .into_diagnostic()?;
+            let mut sidechain_proposal_history = write_txn
+                .open_table(SIDECHAIN_PROPOSAL_HISTORY)
+                .into_diagnostic()?;
+            for (data_hash, sidechain_proposal) in snapshot.sidechain_proposals {
+                data_hash_to_sidechain_proposal
+                    .insert(&data_hash, &sidechain_proposal)
+                    .into_diagnostic()?;
+                sidechain_proposal_history
+                    .insert((&data_hash, tip_height), Some(sidechain_proposal))
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let mut sidechain_number_to_sidechain = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_SIDECHAIN)
+                .into_diagnostic()?;
+            let mut sidechain_history =
+                write_txn.open_table(SIDECHAIN_HISTORY).into_diagnostic()?;
+            for sidechain in snapshot.sidechains {
+                sidechain_number_to_sidechain
+                    .insert(sidechain.sidechain_number, &sidechain)
+                    .into_diagnostic()?;
+                sidechain_history
+                    .insert((sidechain.sidechain_number, tip_height), Some(sidechain))
+                    .into_diagnostic()?;
+            }
+        }
+        {
+            let mut bundle_index_to_bundle = write_txn
+                .open_table(BUNDLE_INDEX_TO_BUNDLE)
+                .into_diagnostic()?;
+            let mut bundle_txid_to_bundle_index = write_txn
+                .open_table(BUNDLE_TXID_TO_BUNDLE_INDEX)
+                .into_diagnostic()?;
+            let mut bundle_history = write_txn.open_table(BUNDLE_HISTORY).into_diagnostic()?;
+            for (sidechain_number, bundles) in snapshot.bundles {
+                for (bundle_index, bundle) in (0..).zip(bundles) {
+                    bundle_index_to_bundle
+                        .insert((sidechain_number, bundle_index), &bundle)
+                        .into_diagnostic()?;
+                    bundle_txid_to_bundle_index
+                        .insert((sidechain_number, &bundle.bundle_txid), bundle_index)
+                        .into_diagnostic()?;
+                    bundle_history
+                        .insert((sidechain_number, bundle_index, tip_height), Some(bundle))
+                        .into_diagnostic()?;
+                }
+            }
+        }
+        {
+            let mut sidechain_number_to_ctip = write_txn
+                .open_table(SIDECHAIN_NUMBER_TO_CTIP)
+                .into_diagnostic()?;
+            let mut ctip_history = write_txn.open_table(CTIP_HISTORY).into_diagnostic()?;
+            for (sidechain_number, ctip) in snapshot.ctips {
+                sidechain_number_to_ctip
+                    .insert(sidechain_number, &ctip)
+                    .into_diagnostic()?;
+                ctip_history
+                    .insert((sidechain_number, tip_height), Some(ctip))
+                    .into_diagnostic()?;
+            }
+        }
+        if !snapshot.previous_votes.is_empty() {
+            write_txn
+                .open_table(PREVIOUS_VOTES)
+                .into_diagnostic()?
+                .insert((), &snapshot.previous_votes)
+                .into_diagnostic()?;
+            write_txn
+                .open_table(PREVIOUS_VOTES_HISTORY)
+                .into_diagnostic()?
+                .insert(tip_height, Some(snapshot.previous_votes))
+                .into_diagnostic()?;
+        }
+        // Dropping the transaction without committing it discards the import.
+        if commitment::state_commitment(&write_txn)? != snapshot.tip.state_commitment {
+            return Err(miette!("snapshot state doesn't match its state commitment"));
+        }
+        write_txn.commit().into_diagnostic()?;
+        Ok(tip_height)
+    }
+}
+
+/// Whether no table holds any state, history or blocks.
+fn is_state_empty(write_txn: &WriteTransaction) -> Result<bool> {
+    fn is_empty<K: RedbKey + 'static, V: RedbValue + 'static>(
+        write_txn: &WriteTransaction,
+        table: TableDefinition<K, V>,
+    ) -> Result<bool> {
+        write_txn
+            .open_table(table)
+            .into_diagnostic()?
+            .is_empty()
+            .into_diagnostic()
+    }
+    Ok(is_empty(write_txn, DATA_HASH_TO_SIDECHAIN_PROPOSAL)?
+        && is_empty(write_txn, SIDECHAIN_NUMBER_TO_SIDECHAIN)?
+        && is_empty(write_txn, BUNDLE_INDEX_TO_BUNDLE)?
+        && is_empty(write_txn, BUNDLE_TXID_TO_BUNDLE_INDEX)?
+        && is_empty(write_txn, SIDECHAIN_NUMBER_TO_CTIP)?
+        && is_empty(write_txn, PREVIOUS_VOTES)?
+        && is_empty(write_txn, BLOCK_HEIGHT_TO_EVENTS)?
+        && is_empty(write_txn, BLOCK_HEIGHT_TO_BLOCK_INFO)?
+        && is_empty(write_txn, SIDECHAIN_PROPOSAL_HISTORY)?
+        && is_empty(write_txn, SIDECHAIN_HISTORY)?
+        && is_empty(write_txn, BUNDLE_HISTORY)?
+        && is_empty(write_txn, CTIP_HISTORY)?
+        && is_empty(write_txn, PREVIOUS_VOTES_HISTORY)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::{CoinbaseMessage, M4AckBundles};
+    use bitcoin::hashes::Hash;
+    use bitcoin::Network;
+    use miette::{IntoDiagnostic, Result};
+
+    use crate::bip300::Bip300;
+    use crate::test_utils::{ctip, hash, sidechain, spend_ctip, TestChain, START_HEIGHT};
+    use crate::types::{ConsensusParams, InitialState, StartBlock};
+
+    /// A chain with a sidechain that got a deposit.
+    fn chain_with_deposit() -> Result<TestChain> {
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: [(0, ctip(1, 1000))].into(),
+        })?;
+        chain.connect(vec![], vec![spend_ctip(0, &ctip(1, 1000), 3000)])?;
+        Ok(chain)
+    }
+
+    #[test]
+    fn snapshot_round_trips() -> Result<()> {
+        let chain = chain_with_deposit()?;
+        let path = chain.dir.path().join("snapshot");
+        let height = chain.bip300.export_snapshot(&path)?;
+
+        let imported = TestChain::new(InitialState::default())?;
+        assert_eq!(imported.bip300.import_snapshot(&path)?, height);
+        assert_eq!(
+            imported.bip300.get_ctip(0, None)?,
+            chain.bip300.get_ctip(0, None)?
+        );
+        assert_eq!(imported.bip300.get_ctip(0, None)?.unwrap().value, 3000);
+        assert_eq!(imported.bip300.get_sidechains(None)?.len(), 1);
+        assert_eq!(
+            imported.bip300.get_chain_tip()?.unwrap().1.state_commitment,
+            chain.bip300.get_chain_tip()?.unwrap().1.state_commitment
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn import_requires_an_empty_database() -> Result<()> {
+        let chain = chain_with_deposit()?;
+        let path = chain.dir.path().join("snapshot");
+        chain.bip300.export_snapshot(&path)?;
+
+        let seeded = TestChain::with_sidechains(&[1])?;
+        seeded.bip300.import_snapshot(&path).unwrap_err();
+        assert!(seeded.bip300.get_chain_tip()?.is_none());
+        chain.bip300.import_snapshot(&path).unwrap_err();
+        Ok(())
+    }
+
+    #[test]
+    fn import_requires_the_same_consensus_params_and_start_block() -> Result<()> {
+        let chain = chain_with_deposit()?;
+        let path = chain.dir.path().join("snapshot");
+        chain.bip300.export_snapshot(&path)?;
+
+        let imported = TestChain::with_consensus_params(
+            InitialState::default(),
+            ConsensusParams {
+                bundle_threshold: 10,
+                ..ConsensusParams::default()
+            },
+        )?;
+        let err = imported.bip300.import_snapshot(&path).unwrap_err();
+        assert!(err.to_string().contains("consensus params"));
+        assert!(imported.bip300.get_chain_tip()?.is_none());
+
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let imported = Bip300::new(
+            &dir.path().join("bip300.redb"),
+            Network::Regtest,
+            ConsensusParams::default(),
+            StartBlock {
+                height: START_HEIGHT + 1,
+                prev_block_hash: chain.blocks[0].block_hash().to_byte_array(),
+            },
+            None,
+        )?;
+        let err = imported.import_snapshot(&path).unwrap_err();
+        assert!(err.to_string().contains("snapshot starts at"));
+        Ok(())
+    }
+
+    #[test]
+    fn previous_votes_survive_a_snapshot() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(
+            vec![CoinbaseMessage::M3ProposeBundle {
+                sidechain_number: 0,
+                bundle_txid: hash(1),
+            }],
+            vec![],
+        )?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: vec![0],
+            })],
+            vec![],
+        )?;
+        let path = chain.dir.path().join("snapshot");
+        chain.bip300.export_snapshot(&path)?;
+
+        let mut imported = TestChain::new(InitialState::default())?;
+        imported.bip300.import_snapshot(&path)?;
+        imported.blocks.clone_from(&chain.blocks);
+        for chain in [&mut chain, &mut imported] {
+            chain.connect(
+                vec![CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)],
+                vec![],
+            )?;
+        }
+        assert_eq!(imported.bip300.get_bundles(0, None)?[0].vote_count, 2);
+        let (_, tip) = chain.bip300.get_chain_tip()?.unwrap();
+        let (_, imported_tip) = imported.bip300.get_chain_tip()?.unwrap();
+        assert_eq!(imported_tip.state_commitment, tip.state_commitment);
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use super::*;
+use redb::{RedbKey, RedbValue, TableHandle, TypeName};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+/// Values encoded with bincode panic when they are read back from invalid data. Checking every
+/// stored value reads the whole database, so only the values read while opening it are checked
+/// then, and the rest by `self-check --deep`.
+pub(super) fn check_metadata(db: &Database) -> Result<()> {
+    let read_txn = db.begin_read().into_diagnostic()?;
+    check_table(&read_txn, CONSENSUS_PARAMS)?;
+    check_table(&read_txn, BLOCK_HEIGHT_TO_BLOCK_INFO)?;
+    Ok(())
+}
+
+pub(super) fn check_values(db: &Database) -> Result<()> {
+    let read_txn = db.begin_read().into_diagnostic()?;
+    check_table(&read_txn, DATA_HASH_TO_SIDECHAIN_PROPOSAL)?;
+    check_table(&read_txn, SIDECHAIN_NUMBER_TO_SIDECHAIN)?;
+    check_table(&read_txn, PREVIOUS_VOTES)?;
+    check_table(&read_txn, BLOCK_HEIGHT_TO_EVENTS)?;
+    check_table(&read_txn, BLOCK_HEIGHT_TO_BLOCK_INFO)?;
+    check_table(&read_txn, CONSENSUS_PARAMS)?;
+    check_table(&read_txn, SIDECHAIN_PROPOSAL_HISTORY)?;
+    check_table(&read_txn, SIDECHAIN_HISTORY)?;
+    check_table(&read_txn, PREVIOUS_VOTES_HISTORY)?;
+    Ok(())
+}
+
+fn check_table<K: RedbKey + 'static, V: CheckEncoding + 'static>(
+    read_txn: &ReadTransaction,
+    table: TableDefinition<K, V>,
+) -> Result<()> {
+    let encoded_table: TableDefinition<K, Encoded<V>> = TableDefinition::new(table.name());
+    for entry in read_txn
+        .open_table(encoded_table)
+        .into_diagnostic()?
+        .iter()
+        .into_diagnostic()?
+    {
+        let (_, value) = entry.into_diagnostic()?;
+        V::check_encoding(value.value())
+            .map_err(|err| miette!("table {table} holds an invalid value: {err}"))?;
+    }
+    Ok(())
+}
+
+/// Reads the values of a table as the bytes they are stored as.
+#[derive(Debug)]
+struct Encoded<V>(PhantomData<V>);
+
+impl<V: RedbValue + 'static> RedbValue for Encoded<V> {
+    type SelfType<'a> = &'a [u8];
+    type AsBytes<'a> = &'a [u8];
+
+    // Tables are only opened with the name and width of the type they were created with.
+    fn type_name() -> TypeName {
+        V::type_name()
+    }
+
+    fn fixed_width() -> Option<usize> {
+        V::fixed_width()
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data
+    }
+}
+
+trait CheckEncoding: RedbValue {
+    /// Whether `data` can be read back as a value.
+    fn check_encoding(data: &[u8]) -> Result<(), String>;
+}
+
+fn check_bincode<T: DeserializeOwned>(data: &[u8]) -> Result<(), String> {
+    bincode::deserialize::<T>(data)
+        .map(drop)
+        .map_err(|err| err.to_string())
+}
+
+impl CheckEncoding for SidechainProposal {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+impl CheckEncoding for Sidechain {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+impl CheckEncoding for Vote {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+impl CheckEncoding for Event {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+impl CheckEncoding for BlockInfo {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+impl CheckEncoding for ConsensusParams {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        check_bincode::<Self>(data)
+    }
+}
+
+/// Encoded by redb as a tag byte, followed by the value if the tag is 1.
+impl<T: CheckEncoding> CheckEncoding for Option<T> {
+    fn check_encoding(data: &[u8]) -> Result<(), String> {
+        match data.split_first() {
+            Some((0, _)) => Ok(()),
+            Some((1, data)) => T::check_encoding(data),
+            _ => Err("invalid option tag".to_owned()),
+        }
+    }
+}
+
+/// Encoded by redb as the number of elements, followed by each element, prefixed with its length
+/// unless it has a fixed width. Lengths are varints: a byte below 254, or 254 or 255 followed by
+/// a little endian u16 or u32.
+impl<T: CheckEncoding> CheckEncoding for Vec<T> {
+    fn check_encoding(mut data: &[u8]) -> Result<(), String> {
+        fn read_len(data: &mut &[u8]) -> Result<usize, String> {
+            let (len, width) = match data.first() {
+                Some(&len @ 0..=253) => (len as usize, 1),
+                Some(254) => (
+                    data.get(1..3)
+                        .map(|len| u16::from_le_bytes(len.try_into().unwrap()) as usize)
+                        .ok_or("truncated length")?,
+                    3,
+                ),
+                Some(255) => (
+                    data.get(1..5)
+                        .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                        .ok_or("truncated length")?,
+                    5,
+                ),
+                None => return Err("missing length".to_owned()),
+            };
+            *data = &data[width..];
+            Ok(len)
+        }
+
+        let elements = read_len(&mut data)?;
+        for _ in 0..elements {
+            let len = match T::fixed_width() {
+                Some(len) => len,
+                None => read_len(&mut data)?,
+            };
+            let element = data.get(..len).ok_or("truncated element")?;
+            T::check_encoding(element)?;
+            data = &data[len..];
+        }
+        if !data.is_empty() {
+            return Err("trailing bytes".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Borrow;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_utils::{TestChain, START_HEIGHT};
+
+    /// Opens the database of a chain with one block after overwriting `key` in `table` with
+    /// `value`.
+    fn open_corrupted<K: RedbKey + 'static, V: CheckEncoding + 'static>(
+        table: TableDefinition<K, V>,
+        key: impl Borrow<K::SelfType<'static>>,
+        value: &[u8],
+    ) -> Result<(TempDir, Result<Bip300>)> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(vec![], vec![])?;
+        let TestChain { bip300, dir, .. } = chain;
+        drop(bip300);
+        let path = dir.path().join("bip300.redb");
+
+        let db = Database::create(&path).into_diagnostic()?;
+        let write_txn = db.begin_write().into_diagnostic()?;
+        let encoded_table: TableDefinition<K, Encoded<V>> = TableDefinition::new(table.name());
+        write_txn
+            .open_table(encoded_table)
+            .into_diagnostic()?
+            .insert(key, value)
+            .into_diagnostic()?;
+        write_txn.commit().into_diagnostic()?;
+        drop(db);
+
+        let bip300 = Bip300::new(
+            &path,
+            Network::Regtest,
+            ConsensusParams::default(),
+            StartBlock {
+                height: START_HEIGHT,
+                prev_block_hash: [0; 32],
+            },
+            None,
+        );
+        Ok((dir, bip300))
+    }
+
+    #[test]
+    fn invalid_metadata_is_refused_on_open() -> Result<()> {
+        let (_dir, bip300) = open_corrupted(CONSENSUS_PARAMS, (), &[1, 2, 3])?;
+        let err = bip300.err().unwrap();
+        assert!(err.to_string().contains("consensus_params"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_values_are_found_by_the_deep_check() -> Result<()> {
+        // One event with an unknown variant.
+        let (_dir, bip300) =
+            open_corrupted(BLOCK_HEIGHT_TO_EVENTS, START_HEIGHT, &[1, 4, 0xFF, 0, 0, 0])?;
+        let err = bip300?.check_values().err().unwrap();
+        assert!(err.to_string().contains("block_height_to_events"));
+        Ok(())
+    }
+
+    #[test]
+    fn vec_lengths_are_checked() {
+        let vote = bincode::serialize(&Vote::Alarm).unwrap();
+        let mut data = vec![1, vote.len() as u8];
+        data.extend(&vote);
+        assert_eq!(Vec::<Vote>::check_encoding(&data), Ok(()));
+        assert!(Vec::<Vote>::check_encoding(&data[..data.len() - 1]).is_err());
+        assert!(Vec::<Vote>::check_encoding(&[data.as_slice(), &[0]].concat()).is_err());
+        assert!(Vec::<Vote>::check_encoding(&[254, 1]).is_err());
+    }
+}
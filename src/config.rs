@@ -0,0 +1,526 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use bitcoin::hashes::Hash;
+use bitcoin::hex::FromHex;
+use bitcoin::{BlockHash, Network, Txid};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use miette::{miette, IntoDiagnostic, Result};
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+use crate::follower::BitcoindConfig;
+use crate::health::DEFAULT_MAX_BLOCKS_BEHIND;
+use crate::policy::VotePolicy;
+use crate::server::DEFAULT_CONNECT_BLOCKS_BATCH_SIZE;
+use crate::types::{ConsensusParams, Hash256, StartBlock};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "[::1]:50051";
+
+const DB_FILE_NAME: &str = "bip300.redb";
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file with the same options as the command line, without the leading dashes.
+    /// Options given on the command line or in the environment take precedence over it.
+    #[arg(long, env = "BIP300_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub options: Options,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Serve the validator over gRPC. This is the default.
+    Serve,
+    /// Sync with bitcoind once and exit.
+    Sync,
+    /// Connect blocks read from the blk*.dat files of a Bitcoin Core data directory.
+    ImportBlocks {
+        /// Bitcoin Core data directory.
+        #[arg(long)]
+        datadir: PathBuf,
+    },
+    /// Write the state at the chain tip to a snapshot file.
+    ExportSnapshot {
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Restore the state from a snapshot file into an empty database.
+    ImportSnapshot {
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Reclaim space left in the database by removed entries.
+    Compact,
+    /// Check the database, which is also done whenever it is opened, and exit.
+    SelfCheck {
+        /// Also check that every stored value can be read back, which reads the whole database.
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Print the BIP300 messages and OP_DRIVECHAIN outputs of a block or transaction as JSON,
+    /// without opening the database.
+    DecodeBip300Messages {
+        /// Consensus encoded block, or coinbase transaction, as hex.
+        hex: String,
+    },
+}
+
+#[derive(Args, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    /// Directory the database is kept in. Networks other than mainnet use a subdirectory of it.
+    /// [default: .]
+    #[arg(long, env = "BIP300_DATA_DIR", global = true)]
+    data_dir: Option<PathBuf>,
+    /// [default: bitcoin]
+    #[arg(long, env = "BIP300_NETWORK", global = true)]
+    network: Option<Network>,
+    /// Address to serve gRPC on. [default: [::1]:50051]
+    #[arg(long, env = "BIP300_LISTEN_ADDRESS", global = true)]
+    listen_address: Option<SocketAddr>,
+    /// Address to serve Prometheus metrics on, at /metrics. They aren't served if unset.
+    #[arg(long, env = "BIP300_METRICS_ADDRESS", global = true)]
+    metrics_address: Option<SocketAddr>,
+    /// PEM certificate to serve gRPC over TLS with. Requires tls-key.
+    #[arg(long, env = "BIP300_TLS_CERT", global = true)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of tls-cert.
+    #[arg(long, env = "BIP300_TLS_KEY", global = true)]
+    tls_key: Option<PathBuf>,
+    /// PEM certificate of the CA that signs client certificates. Clients must present one if set.
+    #[arg(long, env = "BIP300_TLS_CLIENT_CA", global = true)]
+    tls_client_ca: Option<PathBuf>,
+    /// Bearer token required for gRPC methods that only read the state. They are open if unset.
+    #[arg(long, env = "BIP300_READ_TOKEN", global = true, hide_env_values = true)]
+    read_token: Option<String>,
+    /// Bearer token required for gRPC methods that connect or disconnect blocks, which also
+    /// grants read access. They are only open if no token is set at all.
+    #[arg(
+        long,
+        env = "BIP300_WRITE_TOKEN",
+        global = true,
+        hide_env_values = true
+    )]
+    write_token: Option<String>,
+    /// One of off, error, warn, info, debug or trace. [default: info]
+    #[arg(long, env = "BIP300_LOG_LEVEL", global = true)]
+    log_level: Option<String>,
+    /// [default: text]
+    #[arg(long, env = "BIP300_LOG_FORMAT", global = true)]
+    log_format: Option<LogFormat>,
+    /// Number of blocks of history to keep for queries and disconnects. All of it is kept if
+    /// unset.
+    #[arg(long, env = "BIP300_HISTORY_DEPTH", global = true)]
+    history_depth: Option<u32>,
+    /// The health service reports the validator as serving while it's at most this many blocks
+    /// behind bitcoind. [default: 2]
+    #[arg(long, env = "BIP300_HEALTH_MAX_BLOCKS_BEHIND", global = true)]
+    health_max_blocks_behind: Option<u32>,
+    /// Hex data hashes of the sidechain proposals the vote policy acks.
+    #[arg(
+        long,
+        env = "BIP300_ACK_SIDECHAIN_PROPOSALS",
+        global = true,
+        value_delimiter = ','
+    )]
+    ack_sidechain_proposals: Option<Vec<String>>,
+    /// Txids of the bundles the vote policy never upvotes.
+    #[arg(
+        long,
+        env = "BIP300_DENY_BUNDLES",
+        global = true,
+        value_delimiter = ','
+    )]
+    deny_bundles: Option<Vec<Txid>>,
+    /// How the vote policy votes on sidechains whose leading bundle is denied. [default: abstain]
+    #[arg(long, env = "BIP300_DENIED_BUNDLE_VOTE", global = true)]
+    denied_bundle_vote: Option<DeniedBundleVote>,
+    /// Number of blocks connected per write transaction by ConnectBlocks. [default: 1000]
+    #[arg(long, env = "BIP300_CONNECT_BLOCKS_BATCH_SIZE", global = true)]
+    connect_blocks_batch_size: Option<usize>,
+    /// Height of the first block to connect to an empty database.
+    #[arg(long, env = "BIP300_START_HEIGHT", global = true)]
+    start_height: Option<u32>,
+    /// Hash of the block before the start height.
+    #[arg(long, env = "BIP300_START_PREV_BLOCK_HASH", global = true)]
+    start_prev_block_hash: Option<BlockHash>,
+    /// JSON file with sidechains and ctips to start from, for test networks.
+    #[arg(long, env = "BIP300_SEED_STATE", global = true)]
+    seed_state: Option<PathBuf>,
+    /// Votes a bundle needs beyond this to be paid out, for test networks. [default: 13150]
+    #[arg(long, env = "BIP300_BUNDLE_THRESHOLD", global = true)]
+    bundle_threshold: Option<u16>,
+    /// Acks a proposal replacing an active sidechain needs beyond this, for test networks.
+    /// [default: 13150]
+    #[arg(long, env = "BIP300_USED_SIDECHAIN_THRESHOLD", global = true)]
+    used_sidechain_threshold: Option<u16>,
+    /// Blocks a proposal replacing an active sidechain has to reach its threshold, for test
+    /// networks. [default: 26300]
+    #[arg(long, env = "BIP300_USED_SIDECHAIN_MAX_AGE", global = true)]
+    used_sidechain_max_age: Option<u32>,
+    /// Acks a proposal for a free sidechain slot needs beyond this, for test networks.
+    /// [default: 1815]
+    #[arg(long, env = "BIP300_UNUSED_SIDECHAIN_THRESHOLD", global = true)]
+    unused_sidechain_threshold: Option<u16>,
+    /// Blocks a proposal for a free sidechain slot has to reach its threshold, for test
+    /// networks. [default: 2016]
+    #[arg(long, env = "BIP300_UNUSED_SIDECHAIN_MAX_AGE", global = true)]
+    unused_sidechain_max_age: Option<u32>,
+    /// bitcoind to follow. Blocks are only connected through gRPC if unset.
+    #[arg(long, env = "BITCOIND_RPC_HOST", global = true)]
+    bitcoind_rpc_host: Option<String>,
+    /// [default: the network's default RPC port]
+    #[arg(long, env = "BITCOIND_RPC_PORT", global = true)]
+    bitcoind_rpc_port: Option<u16>,
+    #[arg(long, env = "BITCOIND_RPC_USER", global = true)]
+    bitcoind_rpc_user: Option<String>,
+    #[arg(
+        long,
+        env = "BITCOIND_RPC_PASSWORD",
+        global = true,
+        hide_env_values = true
+    )]
+    bitcoind_rpc_password: Option<String>,
+    /// Address of bitcoind's `zmqpubhashblock` publisher.
+    #[arg(long, env = "BITCOIND_ZMQ_ADDRESS", global = true)]
+    bitcoind_zmq_address: Option<String>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeniedBundleVote {
+    /// Leave the sidechain's bundles alone.
+    #[default]
+    Abstain,
+    /// Take a vote away from every bundle of the sidechain.
+    Alarm,
+}
+
+/// Options with defaults filled in.
+pub struct Config {
+    pub db_path: PathBuf,
+    pub network: Network,
+    pub listen_address: SocketAddr,
+    pub metrics_address: Option<SocketAddr>,
+    pub tls: Option<TlsConfig>,
+    pub read_token: Option<String>,
+    pub write_token: Option<String>,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub history_depth: Option<u32>,
+    pub health_max_blocks_behind: u32,
+    pub vote_policy: VotePolicy,
+    pub connect_blocks_batch_size: usize,
+    pub start_block: StartBlock,
+    pub seed_state: Option<PathBuf>,
+    pub consensus_params: ConsensusParams,
+    pub bitcoind: Option<BitcoindConfig>,
+}
+
+/// PEM files to serve gRPC over TLS with.
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Client certificates signed by this CA are required if set.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Bitcoin Core keeps the data of networks other than mainnet in a subdirectory, and so do we.
+pub fn network_dir(network: Network) -> &'static str {
+    match network {
+        Network::Testnet => "testnet3",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+        _ => "",
+    }
+}
+
+fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 18332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443,
+        _ => 8332,
+    }
+}
+
+impl Cli {
+    /// Combines the command line and environment with the config file, if there is one.
+    pub fn config(&self) -> Result<Config> {
+        let file_options = match &self.config {
+            Some(path) => read_config_file(path)?,
+            None => Options::default(),
+        };
+        self.options.clone().or(file_options).resolve()
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<Options> {
+    let config = std::fs::read_to_string(path).into_diagnostic()?;
+    toml::from_str(&config)
+        .into_diagnostic()
+        .map_err(|err| err.wrap_err(format!("invalid config file {}", path.display())))
+}
+
+impl Options {
+    /// Fills the options that aren't set with the ones from `other`.
+    fn or(self, other: Options) -> Options {
+        Options {
+            data_dir: self.data_dir.or(other.data_dir),
+            network: self.network.or(other.network),
+            listen_address: self.listen_address.or(other.listen_address),
+            metrics_address: self.metrics_address.or(other.metrics_address),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            tls_client_ca: self.tls_client_ca.or(other.tls_client_ca),
+            read_token: self.read_token.or(other.read_token),
+            write_token: self.write_token.or(other.write_token),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            history_depth: self.history_depth.or(other.history_depth),
+            health_max_blocks_behind: self
+                .health_max_blocks_behind
+                .or(other.health_max_blocks_behind),
+            ack_sidechain_proposals: self
+                .ack_sidechain_proposals
+                .or(other.ack_sidechain_proposals),
+            deny_bundles: self.deny_bundles.or(other.deny_bundles),
+            denied_bundle_vote: self.denied_bundle_vote.or(other.denied_bundle_vote),
+            connect_blocks_batch_size: self
+                .connect_blocks_batch_size
+                .or(other.connect_blocks_batch_size),
+            start_height: self.start_height.or(other.start_height),
+            start_prev_block_hash: self.start_prev_block_hash.or(other.start_prev_block_hash),
+            seed_state: self.seed_state.or(other.seed_state),
+            bundle_threshold: self.bundle_threshold.or(other.bundle_threshold),
+            used_sidechain_threshold: self
+                .used_sidechain_threshold
+                .or(other.used_sidechain_threshold),
+            used_sidechain_max_age: self.used_sidechain_max_age.or(other.used_sidechain_max_age),
+            unused_sidechain_threshold: self
+                .unused_sidechain_threshold
+                .or(other.unused_sidechain_threshold),
+            unused_sidechain_max_age: self
+                .unused_sidechain_max_age
+                .or(other.unused_sidechain_max_age),
+            bitcoind_rpc_host: self.bitcoind_rpc_host.or(other.bitcoind_rpc_host),
+            bitcoind_rpc_port: self.bitcoind_rpc_port.or(other.bitcoind_rpc_port),
+            bitcoind_rpc_user: self.bitcoind_rpc_user.or(other.bitcoind_rpc_user),
+            bitcoind_rpc_password: self.bitcoind_rpc_password.or(other.bitcoind_rpc_password),
+            bitcoind_zmq_address: self.bitcoind_zmq_address.or(other.bitcoind_zmq_address),
+        }
+    }
+
+    fn resolve(self) -> Result<Config> {
+        let network = self.network.unwrap_or(Network::Bitcoin);
+        let db_path = self
+            .data_dir
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(network_dir(network))
+            .join(DB_FILE_NAME);
+        let listen_address = match self.listen_address {
+            Some(listen_address) => listen_address,
+            None => DEFAULT_LISTEN_ADDRESS.parse().into_diagnostic()?,
+        };
+        let log_level = match &self.log_level {
+            Some(log_level) => log_level
+                .parse()
+                .map_err(|_| miette!("invalid log level {log_level}"))?,
+            None => LevelFilter::INFO,
+        };
+        let start_block = match (self.start_height, self.start_prev_block_hash) {
+            (None, None) => StartBlock::default(),
+            (Some(height), Some(prev_block_hash)) => StartBlock {
+                height,
+                prev_block_hash: prev_block_hash.to_byte_array(),
+            },
+            _ => {
+                return Err(miette!(
+                    "start-height and start-prev-block-hash must be set together"
+                ))
+            }
+        };
+        let defaults = ConsensusParams::default();
+        let consensus_params = ConsensusParams {
+            used_max_age: self.used_sidechain_max_age.unwrap_or(defaults.used_max_age),
+            used_threshold: self
+                .used_sidechain_threshold
+                .unwrap_or(defaults.used_threshold),
+            unused_max_age: self
+                .unused_sidechain_max_age
+                .unwrap_or(defaults.unused_max_age),
+            unused_threshold: self
+                .unused_sidechain_threshold
+                .unwrap_or(defaults.unused_threshold),
+            bundle_threshold: self.bundle_threshold.unwrap_or(defaults.bundle_threshold),
+        };
+        if network == Network::Bitcoin && consensus_params != defaults {
+            return Err(miette!("consensus params can't be changed on mainnet"));
+        }
+        let tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert,
+                key,
+                client_ca: self.tls_client_ca,
+            }),
+            (None, None) if self.tls_client_ca.is_none() => None,
+            (None, None) => return Err(miette!("tls-client-ca requires tls-cert and tls-key")),
+            _ => return Err(miette!("tls-cert and tls-key must be set together")),
+        };
+        let mut acked_sidechain_proposals = HashSet::new();
+        for data_hash in self.ack_sidechain_proposals.unwrap_or_default() {
+            let parsed = Hash256::from_hex(&data_hash)
+                .map_err(|_| miette!("invalid sidechain proposal data hash {data_hash}"))?;
+            acked_sidechain_proposals.insert(parsed);
+        }
+        let vote_policy = VotePolicy {
+            acked_sidechain_proposals,
+            denied_bundles: self
+                .deny_bundles
+                .unwrap_or_default()
+                .into_iter()
+                .map(|bundle_txid| bundle_txid.to_byte_array())
+                .collect(),
+            denied_bundle_vote: self.denied_bundle_vote.unwrap_or_default(),
+        };
+        let bitcoind = self.bitcoind_rpc_host.map(|host| BitcoindConfig {
+            host,
+            port: self
+                .bitcoind_rpc_port
+                .unwrap_or_else(|| default_rpc_port(network)),
+            user: self.bitcoind_rpc_user.unwrap_or_default(),
+            password: self.bitcoind_rpc_password.unwrap_or_default(),
+            zmq_address: self.bitcoind_zmq_address,
+        });
+        Ok(Config {
+            db_path,
+            network,
+            listen_address,
+            metrics_address: self.metrics_address,
+            tls,
+            read_token: self.read_token,
+            write_token: self.write_token,
+            log_level,
+            log_format: self.log_format.unwrap_or_default(),
+            history_depth: self.history_depth,
+            health_max_blocks_behind: self
+                .health_max_blocks_behind
+                .unwrap_or(DEFAULT_MAX_BLOCKS_BEHIND),
+            vote_policy,
+            connect_blocks_batch_size: self
+                .connect_blocks_batch_size
+                .unwrap_or(DEFAULT_CONNECT_BLOCKS_BATCH_SIZE),
+            start_block,
+            seed_state: self.seed_state,
+            consensus_params,
+            bitcoind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(toml: &str) -> Result<Options> {
+        toml::from_str(toml).into_diagnostic()
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_config_file() -> Result<()> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let config_file = dir.path().join("bip300.toml");
+        std::fs::write(
+            &config_file,
+            r#"
+                network = "regtest"
+                log-level = "debug"
+                history-depth = 10
+                connect-blocks-batch-size = 5
+            "#,
+        )
+        .into_diagnostic()?;
+        // No other test reads the environment, so setting it here doesn't race with them.
+        std::env::set_var("BIP300_LOG_LEVEL", "warn");
+        std::env::set_var("BIP300_HISTORY_DEPTH", "20");
+        let cli = Cli::try_parse_from([
+            "bip300_monitor".as_ref(),
+            "--config".as_ref(),
+            config_file.as_os_str(),
+            "--log-level".as_ref(),
+            "error".as_ref(),
+        ])
+        .into_diagnostic();
+        std::env::remove_var("BIP300_LOG_LEVEL");
+        std::env::remove_var("BIP300_HISTORY_DEPTH");
+
+        let config = cli?.config()?;
+        assert_eq!(config.log_level, LevelFilter::ERROR);
+        assert_eq!(config.history_depth, Some(20));
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.connect_blocks_batch_size, 5);
+        assert_eq!(
+            config.listen_address,
+            DEFAULT_LISTEN_ADDRESS.parse().unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn start_height_requires_the_previous_block_hash() -> Result<()> {
+        let prev_block_hash = "00".repeat(31) + "01";
+        let config = options(&format!(
+            "start-height = 5\nstart-prev-block-hash = \"{prev_block_hash}\""
+        ))?
+        .resolve()?;
+        assert_eq!(config.start_block.height, 5);
+        assert_eq!(
+            BlockHash::from_byte_array(config.start_block.prev_block_hash).to_string(),
+            prev_block_hash
+        );
+
+        assert!(options("start-height = 5")?.resolve().is_err());
+        assert!(
+            options(&format!("start-prev-block-hash = \"{prev_block_hash}\""))?
+                .resolve()
+                .is_err()
+        );
+        assert_eq!(options("")?.resolve()?.start_block, StartBlock::default());
+        Ok(())
+    }
+
+    #[test]
+    fn consensus_params_can_only_be_changed_on_test_networks() -> Result<()> {
+        let config = options("network = \"regtest\"\nbundle-threshold = 10")?.resolve()?;
+        assert_eq!(
+            config.consensus_params,
+            ConsensusParams {
+                bundle_threshold: 10,
+                ..ConsensusParams::default()
+            }
+        );
+
+        assert!(options("bundle-threshold = 10")?.resolve().is_err());
+        assert_eq!(
+            options("")?.resolve()?.consensus_params,
+            ConsensusParams::default()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use bip300_messages::{
+    parse_coinbase_script, sha256d, CoinbaseMessage, M4AckBundles, ABSTAIN_ONE_BYTE,
+    ABSTAIN_TWO_BYTES, ALARM_ONE_BYTE, ALARM_TWO_BYTES,
+};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Block, Transaction, Txid};
+use miette::{miette, Result};
+use serde::Serialize;
+
+use crate::bip300::{drivechain_sidechain_number, message_name, message_sidechain_number};
+
+/// BIP300 messages and OP_DRIVECHAIN outputs of a block or transaction, decoded without looking
+/// at the state.
+#[derive(Serialize)]
+pub struct Decoded {
+    pub messages: Vec<DecodedMessage>,
+    pub drivechain_outputs: Vec<DrivechainOutput>,
+}
+
+#[derive(Serialize)]
+pub struct DecodedMessage {
+    /// Coinbase output the message is in.
+    pub vout: u32,
+    pub message: &'static str,
+    pub sidechain_number: Option<u8>,
+    /// The other fields of the message, formatted for reading.
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+/// An output of a non-coinbase transaction starting with OP_DRIVECHAIN. Whether it is a deposit
+/// or a withdrawal depends on the ctip it spends.
+#[derive(Serialize)]
+pub struct DrivechainOutput {
+    pub txid: Txid,
+    pub vout: u32,
+    /// None if the output is malformed, which makes its block invalid.
+    pub sidechain_number: Option<u8>,
+    pub value: u64,
+}
+
+/// Decodes a consensus encoded block, or a single transaction.
+pub fn decode(data: &[u8]) -> Result<Decoded> {
+    let transactions = if let Ok(block) = deserialize::<Block>(data) {
+        block.txdata
+    } else if let Ok(transaction) = deserialize::<Transaction>(data) {
+        vec![transaction]
+    } else {
+        return Err(miette!("data is neither a block nor a transaction"));
+    };
+    let mut decoded = Decoded {
+        messages: vec![],
+        drivechain_outputs: vec![],
+    };
+    for transaction in &transactions {
+        if transaction.is_coinbase() {
+            for (vout, output) in (0..).zip(&transaction.output) {
+                if let Ok((_, message)) = parse_coinbase_script(&output.script_pubkey) {
+                    decoded.messages.push(decode_message(vout, &message));
+                }
+            }
+            continue;
+        }
+        for (vout, output) in (0..).zip(&transaction.output) {
+            if let Some(sidechain_number) = drivechain_sidechain_number(&output.script_pubkey) {
+                decoded.drivechain_outputs.push(DrivechainOutput {
+                    txid: transaction.txid(),
+                    vout,
+                    sidechain_number,
+                    value: output.value.to_sat(),
+                });
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn decode_message(vout: u32, message: &CoinbaseMessage) -> DecodedMessage {
+    let mut fields = BTreeMap::new();
+    match message {
+        CoinbaseMessage::M1ProposeSidechain { data, .. } => {
+            fields.insert("data", data.to_lower_hex_string());
+            fields.insert("data_hash", sha256d(data).to_lower_hex_string());
+        }
+        CoinbaseMessage::M2AckSidechain { data_hash, .. } => {
+            fields.insert("data_hash", data_hash.to_lower_hex_string());
+        }
+        CoinbaseMessage::M3ProposeBundle { bundle_txid, .. } => {
+            fields.insert(
+                "bundle_txid",
+                Txid::from_byte_array(*bundle_txid).to_string(),
+            );
+        }
+        CoinbaseMessage::M4AckBundles(m4) => {
+            let (encoding, upvotes) = match m4 {
+                M4AckBundles::RepeatPrevious => ("repeat_previous", None),
+                M4AckBundles::LeadingBy50 => ("leading_by_50", None),
+                M4AckBundles::OneByte { upvotes } => (
+                    "one_byte",
+                    Some(
+                        upvotes
+                            .iter()
+                            .map(|upvote| match *upvote {
+                                ABSTAIN_ONE_BYTE => "abstain".to_owned(),
+                                ALARM_ONE_BYTE => "alarm".to_owned(),
+                                bundle_index => bundle_index.to_string(),
+                            })
+                            .collect::<Vec<_>>(),
+                    ),
+                ),
+                M4AckBundles::TwoBytes { upvotes } => (
+                    "two_bytes",
+                    Some(
+                        upvotes
+                            .iter()
+                            .map(|upvote| match *upvote {
+                                ABSTAIN_TWO_BYTES => "abstain".to_owned(),
+                                ALARM_TWO_BYTES => "alarm".to_owned(),
+                                bundle_index => bundle_index.to_string(),
+                            })
+                            .collect(),
+                    ),
+                ),
+            };
+            fields.insert("encoding", encoding.to_owned());
+            if let Some(upvotes) = upvotes {
+                // Upvotes are bundle indexes, by sidechain number.
+                fields.insert("upvotes", upvotes.join(", "));
+            }
+        }
+    }
+    DecodedMessage {
+        vout,
+        message: message_name(message),
+        sidechain_number: message_sidechain_number(message),
+        fields,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::OP_DRIVECHAIN;
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Amount, ScriptBuf, TxOut};
+
+    use super::*;
+    use crate::test_utils::{block, ctip, spend_ctip};
+
+    #[test]
+    fn decodes_coinbase_messages_and_drivechain_outputs() -> Result<()> {
+        let mut malformed = spend_ctip(1, &ctip(2, 1000), 2000);
+        malformed.output.push(TxOut {
+            value: Amount::from_sat(1),
+            script_pubkey: ScriptBuf::from_bytes(vec![OP_DRIVECHAIN.to_u8()]),
+        });
+        let block = block(
+            None,
+            vec![
+                CoinbaseMessage::M1ProposeSidechain {
+                    sidechain_number: 1,
+                    data: vec![0xab],
+                },
+                CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                    upvotes: vec![3, ABSTAIN_ONE_BYTE, ALARM_ONE_BYTE],
+                }),
+            ],
+            vec![spend_ctip(0, &ctip(1, 1000), 1500), malformed.clone()],
+        );
+        let decoded = decode(&serialize(&block))?;
+
+        let messages: Vec<_> = decoded
+            .messages
+            .iter()
+            .map(|message| (message.vout, message.message, message.sidechain_number))
+            .collect();
+        assert_eq!(messages, [(0, "M1", Some(1)), (1, "M4", None)]);
+        assert_eq!(decoded.messages[0].fields["data"], "ab");
+        assert_eq!(
+            decoded.messages[0].fields["data_hash"],
+            sha256d(&[0xab]).to_lower_hex_string()
+        );
+        assert_eq!(decoded.messages[1].fields["encoding"], "one_byte");
+        assert_eq!(decoded.messages[1].fields["upvotes"], "3, abstain, alarm");
+
+        let outputs: Vec<_> = decoded
+            .drivechain_outputs
+            .iter()
+            .map(|output| {
+                (
+                    output.txid,
+                    output.vout,
+                    output.sidechain_number,
+                    output.value,
+                )
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                (block.txdata[1].txid(), 0, Some(0), 1500),
+                (malformed.txid(), 0, Some(1), 2000),
+                (malformed.txid(), 1, None, 1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_single_transactions() -> Result<()> {
+        let transaction = spend_ctip(3, &ctip(1, 1000), 500);
+        let decoded = decode(&serialize(&transaction))?;
+        assert!(decoded.messages.is_empty());
+        assert_eq!(decoded.drivechain_outputs.len(), 1);
+        assert_eq!(decoded.drivechain_outputs[0].sidechain_number, Some(3));
+        assert!(decode(&[1, 2, 3]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,505 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::FromHex;
+use bitcoin::{Block, BlockHash};
+use miette::{miette, IntoDiagnostic, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+use ureq_jsonrpc::Client;
+
+use crate::bip300::Bip300;
+
+const SYNC_BATCH_SIZE: usize = 100;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const NEW_BLOCKS_CHANNEL_CAPACITY: usize = 16;
+
+pub struct BitcoindConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    /// Address of bitcoind's `zmqpubhashblock` publisher.
+    pub zmq_address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BlockchainInfo {
+    blocks: u32,
+}
+
+/// Follows the main chain of a bitcoind node, connecting new blocks and disconnecting blocks that
+/// were reorged out.
+pub struct Follower {
+    bip300: Arc<Bip300>,
+    client: Client,
+    poll_interval: Duration,
+    /// Height of bitcoind's tip when it was last seen, if it was seen at all.
+    bitcoind_height: watch::Sender<Option<u32>>,
+    /// Set to true when the monitor is shutting down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Follower {
+    pub fn new(
+        bip300: Arc<Bip300>,
+        config: BitcoindConfig,
+        poll_interval: Duration,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let client = Client {
+            host: config.host,
+            port: config.port,
+            user: config.user,
+            password: config.password,
+            id: "bip300_monitor".into(),
+        };
+        Self {
+            bip300,
+            client,
+            poll_interval,
+            bitcoind_height: watch::channel(None).0,
+            shutdown,
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    pub fn subscribe_bitcoind_height(&self) -> watch::Receiver<Option<u32>> {
+        self.bitcoind_height.subscribe()
+    }
+
+    fn send_request<T: DeserializeOwned>(&self, method: &str, params: &[Value]) -> Result<T> {
+        self.client
+            .send_request(method, params)
+            .into_diagnostic()?
+            .ok_or_else(|| miette!("{method} returned no result"))
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<BlockHash> {
+        let block_hash: String = self.send_request("getblockhash", &[json!(height)])?;
+        BlockHash::from_str(&block_hash).into_diagnostic()
+    }
+
+    fn get_block(&self, block_hash: &BlockHash) -> Result<Block> {
+        let block: String =
+            self.send_request("getblock", &[json!(block_hash.to_string()), json!(0)])?;
+        let block = Vec::<u8>::from_hex(&block).into_diagnostic()?;
+        deserialize(&block).into_diagnostic()
+    }
+
+    /// Brings the stored chain in line with bitcoind's main chain.
+    ///
+    /// On shutdown, the blocks fetched so far are connected and syncing stops.
+    pub fn sync(&self) -> Result<()> {
+        let blockchain_info: BlockchainInfo = self.send_request("getblockchaininfo", &[])?;
+        let bitcoind_height = blockchain_info.blocks;
+        self.bitcoind_height.send_replace(Some(bitcoind_height));
+
+        // Disconnect blocks until the stored tip is in bitcoind's main chain.
+        let mut tip = self.bip300.get_chain_tip()?;
+        while let Some((height, block_info)) = &tip {
+            if *height <= bitcoind_height
+                && self.get_block_hash(*height)?.to_byte_array() == block_info.block_hash
+            {
+                break;
+            }
+            let block = self.get_block(&BlockHash::from_byte_array(block_info.block_hash))?;
+            self.bip300.disconnect_block(&block)?;
+            tip = self.bip300.get_chain_tip()?;
+        }
+
+        let start_height = match tip {
+            Some((height, _)) => height + 1,
+            None => self.bip300.start_block().height,
+        };
+        let mut batch = vec![];
+        for height in start_height..=bitcoind_height {
+            let block_hash = self.get_block_hash(height)?;
+            batch.push((height, self.get_block(&block_hash)?));
+            let shutting_down = self.is_shutting_down();
+            if batch.len() >= SYNC_BATCH_SIZE || height == bitcoind_height || shutting_down {
+                if let Some((height, err)) = self.bip300.connect_blocks(&batch)?.failure {
+                    return Err(err.wrap_err(format!("block at height {height} is invalid")));
+                }
+                info!(height, "synced with bitcoind");
+                batch.clear();
+            }
+            if shutting_down {
+                info!(height, "stopped syncing to shut down");
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs with bitcoind unless the announced `block_hash` is already the stored tip.
+    fn process_new_block(&self, block_hash: Option<BlockHash>) -> Result<()> {
+        if let (Some(block_hash), Some((_, tip))) = (block_hash, self.bip300.get_chain_tip()?) {
+            if block_hash.to_byte_array() == tip.block_hash {
+                return Ok(());
+            }
+        }
+        self.sync()
+    }
+
+    /// Syncs whenever a new block is announced on `new_blocks`, and every poll interval in case
+    /// announcements stop arriving, until the monitor shuts down.
+    ///
+    /// Announcements carry the hash of the new block. Without `new_blocks` the follower only
+    /// polls.
+    pub async fn run(
+        self: Arc<Self>,
+        mut new_blocks: Option<mpsc::Receiver<BlockHash>>,
+    ) -> Result<()> {
+        let mut new_block = None;
+        let mut shutdown = self.shutdown.clone();
+        loop {
+            if self.is_shutting_down() {
+                return Ok(());
+            }
+            let follower = self.clone();
+            let result = tokio::task::spawn_blocking(move || follower.process_new_block(new_block))
+                .await
+                .into_diagnostic()?;
+            if let Err(err) = result {
+                error!("failed to sync with bitcoind: {err:#}");
+            }
+            new_block = None;
+            let new_block_announced = async {
+                match &mut new_blocks {
+                    Some(receiver) => receiver.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                announced = tokio::time::timeout(self.poll_interval, new_block_announced) => {
+                    match announced {
+                        Ok(Some(block_hash)) => new_block = Some(block_hash),
+                        Ok(None) => {
+                            warn!("block notifications stopped, falling back to polling");
+                            new_blocks = None;
+                        }
+                        // Nothing was announced, poll.
+                        Err(_) => {}
+                    }
+                }
+                changed = shutdown.changed() => {
+                    if changed.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use bip300_messages::CoinbaseMessage;
+    use bitcoin::consensus::serialize;
+    use bitcoin::hex::DisplayHex;
+
+    use super::*;
+    use crate::test_utils::{block, TestChain, START_HEIGHT};
+
+    /// Called with the height of every block bitcoind serves.
+    type GetBlockHook = Box<dyn Fn(u32) + Send>;
+
+    /// Answers the JSON-RPC calls the follower makes like bitcoind would.
+    #[derive(Clone, Default)]
+    struct MockBitcoind {
+        /// The main chain, the first block at `START_HEIGHT`.
+        chain: Arc<Mutex<Vec<Block>>>,
+        /// Every block ever in the main chain, by hash.
+        blocks: Arc<Mutex<HashMap<BlockHash, Block>>>,
+        requests: Arc<AtomicUsize>,
+        on_getblock: Arc<Mutex<Option<GetBlockHook>>>,
+    }
+
+    impl MockBitcoind {
+        /// Serves JSON-RPC on a free local port and returns it.
+        fn start(&self) -> u16 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let bitcoind = self.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let bitcoind = bitcoind.clone();
+                    std::thread::spawn(move || bitcoind.serve(stream.unwrap()));
+                }
+            });
+            port
+        }
+
+        fn set_chain(&self, chain: Vec<Block>) {
+            let mut blocks = self.blocks.lock().unwrap();
+            for block in &chain {
+                blocks.insert(block.block_hash(), block.clone());
+            }
+            *self.chain.lock().unwrap() = chain;
+        }
+
+        fn serve(&self, stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            loop {
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                self.requests.fetch_add(1, Ordering::SeqCst);
+                let response = match self.respond(&request["method"], &request["params"]) {
+                    Ok(result) => json!({"result": result, "error": null, "id": request["id"]}),
+                    Err(message) => json!({
+                        "result": null,
+                        "error": {"code": -1, "message": message},
+                        "id": request["id"],
+                    }),
+                };
+                let response = response.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+        }
+
+        fn respond(&self, method: &Value, params: &Value) -> Result<Value, &'static str> {
+            let chain = self.chain.lock().unwrap();
+            match method.as_str().unwrap() {
+                "getblockchaininfo" => Ok(json!({"blocks": START_HEIGHT + chain.len() as u32 - 1})),
+                "getblockhash" => {
+                    let height = params[0].as_u64().unwrap() as u32;
+                    let block = height
+                        .checked_sub(START_HEIGHT)
+                        .and_then(|index| chain.get(index as usize))
+                        .ok_or("Block height out of range")?;
+                    Ok(json!(block.block_hash().to_string()))
+                }
+                "getblock" => {
+                    let block_hash = BlockHash::from_str(params[0].as_str().unwrap()).unwrap();
+                    let block = self
+                        .blocks
+                        .lock()
+                        .unwrap()
+                        .get(&block_hash)
+                        .cloned()
+                        .ok_or("Block not found")?;
+                    drop(chain);
+                    if let Some(on_getblock) = &*self.on_getblock.lock().unwrap() {
+                        on_getblock(block.header.time);
+                    }
+                    Ok(json!(serialize(&block).to_lower_hex_string()))
+                }
+                _ => Err("Method not found"),
+            }
+        }
+    }
+
+    /// Extends `chain` by `count` blocks, each proposing a sidechain with `data`.
+    fn mine(chain: &mut Vec<Block>, count: usize, data: u8) {
+        for _ in 0..count {
+            let message = CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number: 0,
+                data: vec![data],
+            };
+            chain.push(block(chain.last(), vec![message], vec![]));
+        }
+    }
+
+    fn follower(chain: &TestChain, port: u16) -> (Follower, watch::Sender<bool>) {
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let config = BitcoindConfig {
+            host: "127.0.0.1".into(),
+            port,
+            user: "user".into(),
+            password: "password".into(),
+            zmq_address: None,
+        };
+        let follower = Follower::new(
+            chain.bip300.clone(),
+            config,
+            DEFAULT_POLL_INTERVAL,
+            shutdown_receiver,
+        );
+        (follower, shutdown)
+    }
+
+    fn tip(chain: &TestChain) -> Result<Option<(u32, BlockHash)>> {
+        let tip = chain.bip300.get_chain_tip()?;
+        Ok(tip.map(|(height, tip)| (height, BlockHash::from_byte_array(tip.block_hash))))
+    }
+
+    #[test]
+    fn sync_connects_in_batches() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let bitcoind = MockBitcoind::default();
+        let mut blocks = vec![];
+        mine(&mut blocks, 2 * SYNC_BATCH_SIZE + 50, 1);
+        bitcoind.set_chain(blocks.clone());
+        // Heights of the stored tip whenever a block is fetched.
+        let tips = Arc::new(Mutex::new(vec![]));
+        let (bip300, recorded_tips) = (chain.bip300.clone(), tips.clone());
+        *bitcoind.on_getblock.lock().unwrap() = Some(Box::new(move |_| {
+            let tip = bip300.get_chain_tip().unwrap().map(|(height, _)| height);
+            recorded_tips.lock().unwrap().push(tip);
+        }));
+        let (follower, _shutdown) = follower(&chain, bitcoind.start());
+
+        follower.sync()?;
+        let tip_height = START_HEIGHT + blocks.len() as u32 - 1;
+        assert_eq!(
+            tip(&chain)?,
+            Some((tip_height, blocks.last().unwrap().block_hash()))
+        );
+        assert_eq!(
+            *follower.subscribe_bitcoind_height().borrow(),
+            Some(tip_height)
+        );
+        let tips = tips.lock().unwrap();
+        let batch_size = SYNC_BATCH_SIZE as u32;
+        assert_eq!(tips[SYNC_BATCH_SIZE - 1], None);
+        assert_eq!(tips[SYNC_BATCH_SIZE], Some(START_HEIGHT + batch_size - 1));
+        assert_eq!(
+            tips[2 * SYNC_BATCH_SIZE],
+            Some(START_HEIGHT + 2 * batch_size - 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_connects_fetched_blocks_and_stops_on_shutdown() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let bitcoind = MockBitcoind::default();
+        let mut blocks = vec![];
+        mine(&mut blocks, 2 * SYNC_BATCH_SIZE, 1);
+        bitcoind.set_chain(blocks.clone());
+        let (follower, shutdown) = follower(&chain, bitcoind.start());
+        let shutdown = Mutex::new(shutdown);
+        *bitcoind.on_getblock.lock().unwrap() = Some(Box::new(move |height| {
+            if height == START_HEIGHT + 149 {
+                shutdown.lock().unwrap().send_replace(true);
+            }
+        }));
+
+        follower.sync()?;
+        assert_eq!(
+            tip(&chain)?,
+            Some((START_HEIGHT + 149, blocks[149].block_hash()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_disconnects_reorged_blocks() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let bitcoind = MockBitcoind::default();
+        let mut blocks = vec![];
+        mine(&mut blocks, 5, 1);
+        bitcoind.set_chain(blocks.clone());
+        let (follower, _shutdown) = follower(&chain, bitcoind.start());
+        follower.sync()?;
+        assert_eq!(
+            tip(&chain)?,
+            Some((START_HEIGHT + 4, blocks[4].block_hash()))
+        );
+
+        // The new main chain forks off below the stored tip and is shorter than it.
+        blocks.truncate(2);
+        mine(&mut blocks, 1, 2);
+        bitcoind.set_chain(blocks.clone());
+        follower.sync()?;
+        assert_eq!(
+            tip(&chain)?,
+            Some((START_HEIGHT + 2, blocks[2].block_hash()))
+        );
+        let proposals = chain.bip300.get_sidechain_proposals(None)?;
+        // The proposal from the fork block is created, the one from before the fork remains.
+        let mut data: Vec<_> = proposals
+            .into_iter()
+            .map(|(_, proposal)| proposal.data)
+            .collect();
+        data.sort();
+        assert_eq!(data, vec![vec![1], vec![2]]);
+        Ok(())
+    }
+
+    #[test]
+    fn process_new_block_skips_the_stored_tip() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let bitcoind = MockBitcoind::default();
+        let mut blocks = vec![];
+        mine(&mut blocks, 3, 1);
+        bitcoind.set_chain(blocks.clone());
+        let (follower, _shutdown) = follower(&chain, bitcoind.start());
+        follower.sync()?;
+
+        let requests = bitcoind.requests.load(Ordering::SeqCst);
+        follower.process_new_block(Some(blocks[2].block_hash()))?;
+        assert_eq!(bitcoind.requests.load(Ordering::SeqCst), requests);
+        Ok(())
+    }
+
+    #[test]
+    fn process_new_block_syncs_new_blocks() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let bitcoind = MockBitcoind::default();
+        let mut blocks = vec![];
+        mine(&mut blocks, 3, 1);
+        bitcoind.set_chain(blocks.clone());
+        let (follower, _shutdown) = follower(&chain, bitcoind.start());
+        follower.process_new_block(None)?;
+        assert_eq!(
+            tip(&chain)?,
+            Some((START_HEIGHT + 2, blocks[2].block_hash()))
+        );
+
+        // Only the last of two new blocks is announced.
+        mine(&mut blocks, 2, 1);
+        bitcoind.set_chain(blocks.clone());
+        follower.process_new_block(Some(blocks[4].block_hash()))?;
+        assert_eq!(
+            tip(&chain)?,
+            Some((START_HEIGHT + 4, blocks[4].block_hash()))
+        );
+        assert_eq!(
+            *follower.subscribe_bitcoind_height().borrow(),
+            Some(START_HEIGHT + 4)
+        );
+        Ok(())
+    }
+}
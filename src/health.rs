@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use miette::{IntoDiagnostic, Result};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::info;
+
+use crate::bip300::Bip300;
+use crate::server::{bip300::validator_server::ValidatorServer, ValidatorService};
+
+pub const DEFAULT_MAX_BLOCKS_BEHIND: u32 = 2;
+
+/// Keeps the `grpc.health.v1` status of the validator up to date. The reporter must start out
+/// not serving.
+///
+/// The validator is serving while its tip is at most `max_blocks_behind` blocks behind
+/// bitcoind's, and not serving until bitcoind's tip was seen. Without `bitcoind_height` blocks
+/// are only connected through gRPC, so it is always serving.
+pub async fn report_health(
+    bip300: Arc<Bip300>,
+    mut reporter: HealthReporter,
+    bitcoind_height: Option<watch::Receiver<Option<u32>>>,
+    max_blocks_behind: u32,
+) -> Result<()> {
+    let Some(mut bitcoind_height) = bitcoind_height else {
+        set_status(&mut reporter, ServingStatus::Serving).await;
+        return Ok(());
+    };
+    let mut events = bip300.subscribe_events();
+    let mut status = ServingStatus::NotServing;
+    loop {
+        let tip_height = tip_height(&bip300).await?;
+        let synced = match (tip_height, *bitcoind_height.borrow_and_update()) {
+            (None, _) | (_, None) => false,
+            (Some(tip_height), Some(bitcoind_height)) => {
+                tip_height.saturating_add(max_blocks_behind) >= bitcoind_height
+            }
+        };
+        let new_status = if synced {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        if new_status != status {
+            info!(?tip_height, serving = synced, "health status changed");
+            status = new_status;
+            set_status(&mut reporter, status).await;
+        }
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            changed = bitcoind_height.changed() => {
+                if changed.is_err() {
+                    // The follower is gone, so the tip won't move anymore.
+                    set_status(&mut reporter, ServingStatus::NotServing).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Height of the validator's tip, read off the async runtime as it touches the database.
+async fn tip_height(bip300: &Arc<Bip300>) -> Result<Option<u32>> {
+    let start_height = bip300.start_block().height;
+    let bip300 = bip300.clone();
+    let tip = tokio::task::spawn_blocking(move || bip300.get_chain_tip())
+        .await
+        .into_diagnostic()??;
+    Ok(match tip {
+        Some((height, _)) => Some(height),
+        None => start_height.checked_sub(1),
+    })
+}
+
+/// Sets the status of the server as a whole and of the validator service.
+pub async fn set_status(reporter: &mut HealthReporter, status: ServingStatus) {
+    reporter.set_service_status("", status).await;
+    match status {
+        ServingStatus::Serving => {
+            reporter
+                .set_serving::<ValidatorServer<ValidatorService>>()
+                .await
+        }
+        _ => {
+            reporter
+                .set_not_serving::<ValidatorServer<ValidatorService>>()
+                .await
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bitcoin::block::Header;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::pow::Work;
+use bitcoin::{Block, BlockHash, Network};
+use miette::{miette, IntoDiagnostic, Result};
+use tracing::info;
+
+use crate::bip300::Bip300;
+
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+const HEADER_SIZE: usize = 80;
+
+/// Where a block is stored in the `blk*.dat` files.
+#[derive(Clone, Copy)]
+struct BlockLocation {
+    file: usize,
+    offset: usize,
+    size: usize,
+}
+
+struct IndexedHeader {
+    prev_blockhash: BlockHash,
+    work: Work,
+    location: BlockLocation,
+}
+
+/// Raw block files written by Bitcoin Core, deobfuscated with the key in `xor.dat` if there is
+/// one.
+struct BlockFiles {
+    paths: Vec<PathBuf>,
+    xor_key: [u8; 8],
+    cached_file: Option<(usize, Vec<u8>)>,
+}
+
+impl BlockFiles {
+    fn open(blocks_dir: &Path) -> Result<Self> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(blocks_dir).into_diagnostic()? {
+            let path = entry.into_diagnostic()?.path();
+            let is_block_file = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .is_some_and(|file_name| {
+                    file_name.starts_with("blk") && file_name.ends_with(".dat")
+                });
+            if is_block_file {
+                paths.push(path);
+            }
+        }
+        // File names are zero padded, so they sort by file number.
+        paths.sort();
+        if paths.is_empty() {
+            return Err(miette!("no blk*.dat files in {}", blocks_dir.display()));
+        }
+        let xor_key = match fs::read(blocks_dir.join("xor.dat")) {
+            Ok(xor_key) => xor_key
+                .try_into()
+                .map_err(|_| miette!("xor.dat must contain an 8 byte key"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => [0; 8],
+            Err(err) => return Err(err).into_diagnostic(),
+        };
+        Ok(Self {
+            paths,
+            xor_key,
+            cached_file: None,
+        })
+    }
+
+    fn read_file(&mut self, file: usize) -> Result<&[u8]> {
+        if self.cached_file.as_ref().map(|(cached, _)| *cached) != Some(file) {
+            let mut data = fs::read(&self.paths[file]).into_diagnostic()?;
+            if self.xor_key != [0; 8] {
+                for (offset, byte) in data.iter_mut().enumerate() {
+                    *byte ^= self.xor_key[offset % self.xor_key.len()];
+                }
+            }
+            self.cached_file = Some((file, data));
+        }
+        let (_, data) = self.cached_file.as_ref().unwrap();
+        Ok(data)
+    }
+
+    /// Reads the header of every block in every file.
+    fn index(&mut self, magic: [u8; 4]) -> Result<HashMap<BlockHash, IndexedHeader>> {
+        let mut headers = HashMap::new();
+        for file in 0..self.paths.len() {
+            let data = self.read_file(file)?;
+            let mut offset = 0;
+            // Each record is the network magic, the block size and the block. Files are
+            // preallocated, so they may end with zeroes.
+            while offset + 8 + HEADER_SIZE <= data.len() && data[offset..offset + 4] == magic {
+                let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+                let size = size as usize;
+                let block_offset = offset + 8;
+                if block_offset + size > data.len() {
+                    break;
+                }
+                let header: Header = deserialize(&data[block_offset..block_offset + HEADER_SIZE])
+                    .into_diagnostic()?;
+                let location = BlockLocation {
+                    file,
+                    offset: block_offset,
+                    size,
+                };
+                headers.insert(
+                    header.block_hash(),
+                    IndexedHeader {
+                        prev_blockhash: header.prev_blockhash,
+                        work: header.work(),
+                        location,
+                    },
+                );
+                offset = block_offset + size;
+            }
+        }
+        Ok(headers)
+    }
+
+    fn read_block(&mut self, location: BlockLocation) -> Result<Block> {
+        let data = self.read_file(location.file)?;
+        deserialize(&data[location.offset..location.offset + location.size]).into_diagnostic()
+    }
+}
+
+/// Orders the indexed blocks into the chain with the most work, starting at genesis.
+fn best_chain(
+    headers: &HashMap<BlockHash, IndexedHeader>,
+    genesis_hash: BlockHash,
+) -> Result<Vec<BlockLocation>> {
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    for (block_hash, header) in headers {
+        children
+            .entry(header.prev_blockhash)
+            .or_default()
+            .push(*block_hash);
+    }
+    let genesis = headers
+        .get(&genesis_hash)
+        .ok_or_else(|| miette!("the genesis block wasn't found in the block files"))?;
+    // Walk the block tree from genesis, keeping track of the total work of each block.
+    let mut best = (genesis.work, genesis_hash);
+    let mut to_visit = vec![(genesis.work, genesis_hash)];
+    while let Some((chain_work, block_hash)) = to_visit.pop() {
+        if chain_work > best.0 {
+            best = (chain_work, block_hash);
+        }
+        for child in children.get(&block_hash).into_iter().flatten() {
+            to_visit.push((chain_work + headers[child].work, *child));
+        }
+    }
+    let mut chain = vec![];
+    let mut block_hash = best.1;
+    loop {
+        let header = &headers[&block_hash];
+        chain.push(header.location);
+        if block_hash == genesis_hash {
+            break;
+        }
+        block_hash = header.prev_blockhash;
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Connects the blocks in `blocks_dir`, following the chain with the most work, from the start
+/// block or the block after the stored tip.
+pub fn import_blocks(bip300: &Bip300, network: Network, blocks_dir: &Path) -> Result<()> {
+    let mut block_files = BlockFiles::open(blocks_dir)?;
+    info!(files = block_files.paths.len(), "indexing block files");
+    let headers = block_files.index(network.magic().to_bytes())?;
+    let genesis_hash = genesis_block(network).block_hash();
+    let chain = best_chain(&headers, genesis_hash)?;
+    info!(blocks = chain.len(), "found the chain with the most work");
+
+    let start_height = match bip300.get_chain_tip()? {
+        Some((tip_height, tip)) => {
+            let location = chain.get(tip_height as usize).ok_or_else(|| {
+                miette!("the block files don't reach the stored tip at height {tip_height}")
+            })?;
+            let block = block_files.read_block(*location)?;
+            if block.block_hash().to_byte_array() != tip.block_hash {
+                return Err(miette!(
+                    "the stored tip at height {tip_height} isn't in the block files' best chain"
+                ));
+            }
+            tip_height + 1
+        }
+        None => bip300.start_block().height,
+    };
+
+    let mut batch = vec![];
+    for (height, location) in chain.iter().enumerate().skip(start_height as usize) {
+        let height = height as u32;
+        batch.push((height, block_files.read_block(*location)?));
+        if batch.len() >= IMPORT_BATCH_SIZE || height as usize == chain.len() - 1 {
+            if let Some((height, err)) = bip300.connect_blocks(&batch)?.failure {
+                return Err(err.wrap_err(format!("block at height {height} is invalid")));
+            }
+            info!(height, "imported blocks");
+            batch.clear();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::CoinbaseMessage;
+    use bitcoin::consensus::serialize;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_utils::{block, connect_block};
+    use crate::types::{ConsensusParams, StartBlock};
+
+    /// The regtest genesis block, followed by three blocks on the best chain.
+    fn best_chain_blocks() -> Vec<Block> {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for _ in 0..3 {
+            blocks.push(block(blocks.last(), vec![], vec![]));
+        }
+        blocks
+    }
+
+    /// A block forking off after genesis that isn't on the best chain.
+    fn stale_block() -> Block {
+        let message = CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number: 0,
+            data: vec![1],
+        };
+        block(
+            Some(&genesis_block(Network::Regtest)),
+            vec![message],
+            vec![],
+        )
+    }
+
+    fn write_block_file(path: &Path, blocks: &[&Block], xor_key: [u8; 8]) -> Result<()> {
+        let mut data = vec![];
+        for block in blocks {
+            let block = serialize(*block);
+            data.extend(Network::Regtest.magic().to_bytes());
+            data.extend((block.len() as u32).to_le_bytes());
+            data.extend(block);
+        }
+        // Preallocated space that hasn't been written to yet.
+        data.extend([0; 64]);
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte ^= xor_key[offset % xor_key.len()];
+        }
+        fs::write(path, data).into_diagnostic()
+    }
+
+    /// Writes the best chain out of order across two files, with the stale block in between.
+    fn write_block_files(xor_key: Option<[u8; 8]>) -> Result<TempDir> {
+        let blocks_dir = tempfile::tempdir().into_diagnostic()?;
+        if let Some(xor_key) = xor_key {
+            fs::write(blocks_dir.path().join("xor.dat"), xor_key).into_diagnostic()?;
+        }
+        let xor_key = xor_key.unwrap_or([0; 8]);
+        let blocks = best_chain_blocks();
+        let stale_block = stale_block();
+        write_block_file(
+            &blocks_dir.path().join("blk00000.dat"),
+            &[&blocks[0], &blocks[2], &stale_block],
+            xor_key,
+        )?;
+        write_block_file(
+            &blocks_dir.path().join("blk00001.dat"),
+            &[&blocks[3], &blocks[1]],
+            xor_key,
+        )?;
+        Ok(blocks_dir)
+    }
+
+    /// Opens an empty database that starts at the best chain block at `start_height`.
+    fn open_bip300(start_height: u32) -> Result<(TempDir, Bip300)> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let prev_block_hash = match start_height.checked_sub(1) {
+            Some(height) => best_chain_blocks()[height as usize]
+                .block_hash()
+                .to_byte_array(),
+            None => [0; 32],
+        };
+        let start_block = StartBlock {
+            height: start_height,
+            prev_block_hash,
+        };
+        let bip300 = Bip300::new(
+            &dir.path().join("bip300.redb"),
+            Network::Regtest,
+            ConsensusParams::default(),
+            start_block,
+            None,
+        )?;
+        Ok((dir, bip300))
+    }
+
+    fn assert_best_chain_connected(bip300: &Bip300, start_height: u32) -> Result<()> {
+        for (height, block) in best_chain_blocks().iter().enumerate() {
+            let height = height as u32;
+            let block_hash = bip300
+                .get_block_info(height)?
+                .map(|block_info| BlockHash::from_byte_array(block_info.block_hash));
+            let expected = (height >= start_height).then(|| block.block_hash());
+            assert_eq!(block_hash, expected);
+        }
+        assert!(bip300.get_sidechain_proposals(None)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn best_chain_is_connected_in_order() -> Result<()> {
+        let (_dir, bip300) = open_bip300(0)?;
+        let blocks_dir = write_block_files(None)?;
+
+        import_blocks(&bip300, Network::Regtest, blocks_dir.path())?;
+        assert_best_chain_connected(&bip300, 0)
+    }
+
+    #[test]
+    fn obfuscated_block_files_are_read() -> Result<()> {
+        let (_dir, bip300) = open_bip300(0)?;
+        let blocks_dir = write_block_files(Some([1, 2, 3, 4, 5, 6, 7, 8]))?;
+
+        import_blocks(&bip300, Network::Regtest, blocks_dir.path())?;
+        assert_best_chain_connected(&bip300, 0)
+    }
+
+    #[test]
+    fn import_begins_at_the_start_block() -> Result<()> {
+        let (_dir, bip300) = open_bip300(2)?;
+        let blocks_dir = write_block_files(None)?;
+
+        import_blocks(&bip300, Network::Regtest, blocks_dir.path())?;
+        assert_best_chain_connected(&bip300, 2)?;
+        // Importing again resumes after the stored tip, which has nothing left to connect.
+        import_blocks(&bip300, Network::Regtest, blocks_dir.path())?;
+        assert_best_chain_connected(&bip300, 2)
+    }
+
+    #[test]
+    fn stored_tip_must_be_on_the_best_chain() -> Result<()> {
+        let (_dir, bip300) = open_bip300(0)?;
+        connect_block(&bip300, &genesis_block(Network::Regtest), 0)?;
+        connect_block(&bip300, &stale_block(), 1)?;
+        let blocks_dir = write_block_files(None)?;
+
+        let err = import_blocks(&bip300, Network::Regtest, blocks_dir.path())
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("isn't in the block files' best chain"));
+        Ok(())
+    }
+}
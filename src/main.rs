@@ -1,57 +1,291 @@
-use std::time::SystemTime;
+use std::sync::Arc;
 
-use bitcoin::{
-    absolute::{Height, LockTime},
-    block::Header,
-    hashes::Hash,
-    Block, BlockHash, CompactTarget, Transaction, TxMerkleNode,
-};
-use miette::{IntoDiagnostic, Result};
+use bitcoin::{hashes::Hash, hex::FromHex, BlockHash};
+use clap::Parser;
+use miette::{miette, IntoDiagnostic, Result};
 
+mod auth;
+mod bip300;
+mod config;
+mod decode;
+mod follower;
+mod health;
+mod import;
+mod metrics;
+mod policy;
+mod psbt;
 mod server;
+#[cfg(test)]
+mod test_utils;
 mod types;
-mod bip300;
+mod zmq;
+
+use auth::AuthInterceptor;
+use config::{network_dir, Cli, Command, Config, LogFormat, TlsConfig};
+use follower::{Follower, DEFAULT_POLL_INTERVAL, NEW_BLOCKS_CHANNEL_CAPACITY};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use types::InitialState;
+use zmq::ZmqListener;
+
+use server::{bip300::validator_server::ValidatorServer, Bip300, ValidatorService};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic_health::ServingStatus;
+use tracing::{error, info};
+
+/// Opens the database, seeding it with the configured initial state if `seed` is set and no
+/// blocks were connected yet.
+fn open_bip300(config: &Config, seed: bool) -> Result<Bip300> {
+    let bip300 = Bip300::new(
+        &config.db_path,
+        config.network,
+        config.consensus_params,
+        config.start_block,
+        config.history_depth,
+    )?;
+    if let Some(seed_state) = config.seed_state.as_ref().filter(|_| seed) {
+        if bip300.get_chain_tip()?.is_none() {
+            let initial_state = std::fs::read_to_string(seed_state).into_diagnostic()?;
+            let initial_state: InitialState =
+                serde_json::from_str(&initial_state).into_diagnostic()?;
+            bip300.seed_state(initial_state)?;
+        }
+    }
+    // A crash can't leave a half-applied block behind, but make sure before using the database.
+    bip300.check_consistency()?;
+    Ok(bip300)
+}
+
+/// Returns a receiver that changes to true once SIGINT or SIGTERM is received.
+fn shutdown_on_signal() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let interrupt = tokio::signal::ctrl_c();
+        #[cfg(unix)]
+        {
+            let mut terminate = signal(SignalKind::terminate()).expect("failed to handle SIGTERM");
+            tokio::select! {
+                _ = interrupt => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = interrupt.await;
+        info!("shutting down");
+        sender.send_replace(true);
+    });
+    receiver
+}
+
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    // The sender is only dropped after sending true.
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
 
-use server::{bip300::validator_server::ValidatorServer, Bip300};
-use tonic::transport::Server;
+fn init_logging(config: &Config) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log_level);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let coinbase = Transaction {
-        input: vec![],
-        output: vec![],
-        version: bitcoin::transaction::Version(0),
-        lock_time: LockTime::Blocks(Height::ZERO),
-    };
-
-    let now = std::time::SystemTime::now();
-
-    let txdata = vec![coinbase];
-    let header = Header {
-        bits: CompactTarget::from_consensus(0),
-        prev_blockhash: BlockHash::all_zeros(),
-        merkle_root: TxMerkleNode::all_zeros(),
-        nonce: 0,
-        time: now
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .into_diagnostic()?
-            .as_secs() as u32,
-        version: bitcoin::block::Version::NO_SOFT_FORK_SIGNALLING,
-    };
-
-    let block = Block { header, txdata };
-    dbg!(block);
-
-    let addr = "[::1]:50051".parse().into_diagnostic()?;
-    println!("Listening for gRPC on {addr}");
-
-    let bip300 = Bip300::new()?;
-
-    Server::builder()
-        .add_service(ValidatorServer::new(bip300))
-        .serve(addr)
+    let cli = Cli::parse();
+    let config = cli.config()?;
+    init_logging(&config);
+    if let Some(Command::DecodeBip300Messages { hex }) = &cli.command {
+        let data = Vec::<u8>::from_hex(hex.trim()).into_diagnostic()?;
+        let decoded = decode::decode(&data)?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&decoded).into_diagnostic()?
+        );
+        return Ok(());
+    }
+    // Snapshots can only be imported into an empty database, and replace the seed state.
+    let seed = !matches!(cli.command, Some(Command::ImportSnapshot { .. }));
+    let mut bip300 = open_bip300(&config, seed)?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(bip300, config).await,
+        Command::Sync => {
+            let Some(bitcoind_config) = config.bitcoind else {
+                return Err(miette!("bitcoind-rpc-host must be set to sync"));
+            };
+            let follower = Follower::new(
+                Arc::new(bip300),
+                bitcoind_config,
+                DEFAULT_POLL_INTERVAL,
+                shutdown_on_signal(),
+            );
+            tokio::task::spawn_blocking(move || follower.sync())
+                .await
+                .into_diagnostic()?
+        }
+        Command::ImportBlocks { datadir } => {
+            let blocks_dir = datadir.join(network_dir(config.network)).join("blocks");
+            import::import_blocks(&bip300, config.network, &blocks_dir)
+        }
+        Command::ExportSnapshot { output } => {
+            let height = bip300.export_snapshot(&output)?;
+            info!(height, path = %output.display(), "exported snapshot");
+            Ok(())
+        }
+        Command::ImportSnapshot { input } => {
+            let height = bip300.import_snapshot(&input)?;
+            info!(height, path = %input.display(), "imported snapshot");
+            Ok(())
+        }
+        Command::DecodeBip300Messages { .. } => unreachable!("decoded before opening the database"),
+        Command::Compact => {
+            if bip300.compact()? {
+                info!(path = %config.db_path.display(), "compacted database");
+            }
+            Ok(())
+        }
+        Command::SelfCheck { deep } => {
+            if deep {
+                bip300.check_values()?;
+            }
+            info!(path = %config.db_path.display(), deep, "database passed the checks");
+            Ok(())
+        }
+    }
+}
+
+/// Prints what the database holds before serving from it.
+fn self_check(bip300: &Bip300) -> Result<()> {
+    match bip300.get_chain_tip()? {
+        Some((height, tip)) => info!(
+            height,
+            block_hash = %BlockHash::from_byte_array(tip.block_hash),
+            "chain tip"
+        ),
+        None => info!("no blocks connected yet"),
+    }
+    let sidechains = bip300.get_sidechains(None)?;
+    info!(count = sidechains.len(), "active sidechains");
+    for sidechain in sidechains {
+        let ctip_value = bip300
+            .get_ctip(sidechain.sidechain_number, None)?
+            .map(|ctip| ctip.value)
+            .unwrap_or(0);
+        let bundles = bip300.get_bundles(sidechain.sidechain_number, None)?;
+        info!(
+            sidechain_number = sidechain.sidechain_number,
+            activation_height = sidechain.activation_height,
+            ctip_value,
+            pending_bundles = bundles.len(),
+            "sidechain"
+        );
+    }
+    Ok(())
+}
+
+fn server_tls_config(tls: &TlsConfig) -> Result<ServerTlsConfig> {
+    let cert = std::fs::read(&tls.cert).into_diagnostic()?;
+    let key = std::fs::read(&tls.key).into_diagnostic()?;
+    let mut tls_config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Some(client_ca) = &tls.client_ca {
+        let client_ca = std::fs::read(client_ca).into_diagnostic()?;
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+    }
+    Ok(tls_config)
+}
+
+async fn serve(bip300: Bip300, config: Config) -> Result<()> {
+    self_check(&bip300)?;
+
+    let addr = config.listen_address;
+    info!(%addr, "listening for gRPC");
+
+    let bip300 = Arc::new(bip300);
+    let shutdown = shutdown_on_signal();
+
+    if let Some(metrics_address) = config.metrics_address {
+        let bip300 = bip300.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve_metrics(bip300, metrics_address).await {
+                error!("metrics server failed: {err:#}");
+            }
+        });
+    }
+
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health::set_status(&mut health_reporter, ServingStatus::NotServing).await;
+    let mut bitcoind_height = None;
+    let mut follower_task = None;
+
+    if let Some(mut bitcoind_config) = config.bitcoind {
+        let new_blocks = bitcoind_config.zmq_address.take().map(|zmq_address| {
+            let (sender, receiver) = mpsc::channel(NEW_BLOCKS_CHANNEL_CAPACITY);
+            tokio::spawn(async move {
+                if let Err(err) = ZmqListener::new(zmq_address, sender).run().await {
+                    error!("block notification listener failed: {err:#}");
+                }
+            });
+            receiver
+        });
+        let follower = Follower::new(
+            bip300.clone(),
+            bitcoind_config,
+            DEFAULT_POLL_INTERVAL,
+            shutdown.clone(),
+        );
+        bitcoind_height = Some(follower.subscribe_bitcoind_height());
+        follower_task = Some(tokio::spawn(Arc::new(follower).run(new_blocks)));
+    }
+
+    {
+        let bip300 = bip300.clone();
+        let max_blocks_behind = config.health_max_blocks_behind;
+        tokio::spawn(async move {
+            let result =
+                health::report_health(bip300, health_reporter, bitcoind_height, max_blocks_behind)
+                    .await;
+            if let Err(err) = result {
+                error!("health reporting failed: {err:#}");
+            }
+        });
+    }
+
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(server::bip300::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .into_diagnostic()?;
+
+    let mut server = Server::builder();
+    if let Some(tls) = &config.tls {
+        server = server
+            .tls_config(server_tls_config(tls)?)
+            .into_diagnostic()?;
+    }
+    let validator_service = ValidatorServer::with_interceptor(
+        ValidatorService::new(
+            bip300,
+            config.vote_policy,
+            config.connect_blocks_batch_size,
+            shutdown.clone(),
+        ),
+        AuthInterceptor::new(config.read_token, config.write_token),
+    );
+    // Requests in flight when shutting down are answered first, so blocks being connected are
+    // either committed or aborted.
+    server
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_service(validator_service)
+        .serve_with_shutdown(addr, wait_for_shutdown(shutdown))
         .await
         .into_diagnostic()?;
 
+    // Let the follower commit the blocks it already fetched.
+    if let Some(follower_task) = follower_task {
+        follower_task.await.into_diagnostic()??;
+    }
+    info!("shut down");
     Ok(())
 }
@@ -0,0 +1,230 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bitcoin::hashes::Hash;
+use bitcoin::Txid;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use miette::{IntoDiagnostic, Result};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tracing::{error, info};
+
+use crate::bip300::Bip300;
+
+/// Prometheus metrics of a `Bip300`.
+///
+/// Counters are updated as blocks are connected. Gauges describing the state are read from the
+/// database whenever the metrics are scraped, so they are right after restarts and disconnects.
+pub struct Metrics {
+    registry: Registry,
+    blocks_connected: IntCounter,
+    blocks_disconnected: IntCounter,
+    connect_block_seconds: Histogram,
+    messages: IntCounterVec,
+    blocks_rejected: IntCounterVec,
+    chain_tip_height: IntGauge,
+    active_sidechains: IntGauge,
+    pending_sidechain_proposals: IntGauge,
+    ctip_value: IntGaugeVec,
+    bundle_vote_count: IntGaugeVec,
+    bundle_threshold: IntGauge,
+    /// Held while the state gauges are set and gathered, so that a concurrent scrape can't
+    /// gather them between another scrape's reset and set.
+    scrape: Mutex<()>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("bip300".into()), None).unwrap();
+        let metrics = Self {
+            blocks_connected: IntCounter::new("blocks_connected_total", "Blocks connected.")
+                .unwrap(),
+            blocks_disconnected: IntCounter::new(
+                "blocks_disconnected_total",
+                "Blocks disconnected.",
+            )
+            .unwrap(),
+            connect_block_seconds: Histogram::with_opts(HistogramOpts::new(
+                "connect_block_seconds",
+                "Time taken to validate and apply a block.",
+            ))
+            .unwrap(),
+            messages: IntCounterVec::new(
+                Opts::new("messages_total", "BIP300 messages processed, by type."),
+                &["message"],
+            )
+            .unwrap(),
+            blocks_rejected: IntCounterVec::new(
+                Opts::new(
+                    "blocks_rejected_total",
+                    "Blocks rejected, by the rule they broke.",
+                ),
+                &["rule"],
+            )
+            .unwrap(),
+            chain_tip_height: IntGauge::new("chain_tip_height", "Height of the chain tip.")
+                .unwrap(),
+            active_sidechains: IntGauge::new("active_sidechains", "Active sidechains.").unwrap(),
+            pending_sidechain_proposals: IntGauge::new(
+                "pending_sidechain_proposals",
+                "Sidechain proposals that are neither activated nor expired.",
+            )
+            .unwrap(),
+            ctip_value: IntGaugeVec::new(
+                Opts::new(
+                    "ctip_value_sats",
+                    "Value locked in the ctip of each sidechain.",
+                ),
+                &["sidechain"],
+            )
+            .unwrap(),
+            bundle_vote_count: IntGaugeVec::new(
+                Opts::new(
+                    "bundle_vote_count",
+                    "Votes of each pending withdrawal bundle.",
+                ),
+                &["sidechain", "bundle_txid"],
+            )
+            .unwrap(),
+            bundle_threshold: IntGauge::new(
+                "bundle_threshold",
+                "Votes a bundle needs to exceed to be approved.",
+            )
+            .unwrap(),
+            registry,
+            scrape: Mutex::new(()),
+        };
+        metrics.register().unwrap();
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.blocks_connected.clone()))?;
+        self.registry
+            .register(Box::new(self.blocks_disconnected.clone()))?;
+        self.registry
+            .register(Box::new(self.connect_block_seconds.clone()))?;
+        self.registry.register(Box::new(self.messages.clone()))?;
+        self.registry
+            .register(Box::new(self.blocks_rejected.clone()))?;
+        self.registry
+            .register(Box::new(self.chain_tip_height.clone()))?;
+        self.registry
+            .register(Box::new(self.active_sidechains.clone()))?;
+        self.registry
+            .register(Box::new(self.pending_sidechain_proposals.clone()))?;
+        self.registry.register(Box::new(self.ctip_value.clone()))?;
+        self.registry
+            .register(Box::new(self.bundle_vote_count.clone()))?;
+        self.registry
+            .register(Box::new(self.bundle_threshold.clone()))?;
+        Ok(())
+    }
+
+    /// Records a connected block along with the BIP300 messages it contained.
+    pub fn block_connected(&self, duration: Duration, messages: &[&'static str]) {
+        self.blocks_connected.inc();
+        self.connect_block_seconds.observe(duration.as_secs_f64());
+        for message in messages {
+            self.messages.with_label_values(&[message]).inc();
+        }
+    }
+
+    pub fn block_disconnected(&self) {
+        self.blocks_disconnected.inc();
+    }
+
+    pub fn block_rejected(&self, rule: &'static str) {
+        self.blocks_rejected.with_label_values(&[rule]).inc();
+    }
+
+    /// Reads the state gauges from `bip300` and encodes all metrics in the text format.
+    pub fn encode(&self, bip300: &Bip300) -> Result<String> {
+        let _scrape = self.scrape.lock().unwrap();
+        let tip_height = bip300.get_chain_tip()?.map(|(height, _)| height);
+        self.chain_tip_height
+            .set(tip_height.map(i64::from).unwrap_or(-1));
+        let sidechains = bip300.get_sidechains(None)?;
+        self.active_sidechains.set(sidechains.len() as i64);
+        self.pending_sidechain_proposals
+            .set(bip300.get_sidechain_proposals(None)?.len() as i64);
+        self.bundle_threshold
+            .set(bip300.consensus_params().bundle_threshold.into());
+        // Bundles that were paid out and sidechains that were disconnected must not linger.
+        self.ctip_value.reset();
+        self.bundle_vote_count.reset();
+        for sidechain in &sidechains {
+            let sidechain_number = sidechain.sidechain_number.to_string();
+            if let Some(ctip) = bip300.get_ctip(sidechain.sidechain_number, None)? {
+                self.ctip_value
+                    .with_label_values(&[&sidechain_number])
+                    .set(ctip.value as i64);
+            }
+            for bundle in bip300.get_bundles(sidechain.sidechain_number, None)? {
+                let bundle_txid = Txid::from_byte_array(bundle.bundle_txid).to_string();
+                self.bundle_vote_count
+                    .with_label_values(&[&sidechain_number, &bundle_txid])
+                    .set(bundle.vote_count.into());
+            }
+        }
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .into_diagnostic()?;
+        String::from_utf8(buffer).into_diagnostic()
+    }
+}
+
+/// Serves the metrics of `bip300` over HTTP at `/metrics`.
+pub async fn serve_metrics(bip300: Arc<Bip300>, address: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let bip300 = bip300.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let bip300 = bip300.clone();
+                async move { Ok::<_, Infallible>(handle_request(&bip300, request).await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&address)
+        .into_diagnostic()?
+        .serve(make_service);
+    info!(%address, "serving metrics");
+    server.await.into_diagnostic()
+}
+
+async fn handle_request(bip300: &Arc<Bip300>, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    // Reading the state touches the database, so keep it off the async runtime.
+    let bip300 = bip300.clone();
+    let metrics = tokio::task::spawn_blocking(move || bip300.metrics().encode(&bip300)).await;
+    match metrics {
+        Ok(Ok(metrics)) => Response::builder()
+            .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(metrics))
+            .unwrap(),
+        Ok(Err(err)) => {
+            error!("failed to read metrics: {err:#}");
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(err) => {
+            error!("failed to read metrics: {err}");
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+
+use bip300_messages::M4AckBundles;
+use miette::Result;
+
+use crate::bip300::Bip300;
+use crate::config::DeniedBundleVote;
+use crate::psbt::smallest_ack_bundles;
+use crate::types::{Hash256, Vote};
+
+/// How the validator votes when GetCoinbasePSBT is asked to apply its policy.
+#[derive(Default)]
+pub struct VotePolicy {
+    /// Data hashes of the sidechain proposals to ack.
+    pub acked_sidechain_proposals: HashSet<Hash256>,
+    /// Txids of the bundles never to upvote.
+    pub denied_bundles: HashSet<Hash256>,
+    /// Vote on sidechains whose leading bundle is denied.
+    pub denied_bundle_vote: DeniedBundleVote,
+}
+
+impl VotePolicy {
+    /// Sidechain numbers and data hashes of the pending proposals the policy acks.
+    pub fn ack_sidechains(&self, bip300: &Bip300) -> Result<Vec<(u8, Hash256)>> {
+        let acks = bip300
+            .get_sidechain_proposals(None)?
+            .into_iter()
+            .filter(|(data_hash, _)| self.acked_sidechain_proposals.contains(data_hash))
+            .map(|(data_hash, proposal)| (proposal.sidechain_number, data_hash))
+            .collect();
+        Ok(acks)
+    }
+
+    /// Votes, indexed by sidechain number, upvoting the bundle of each active sidechain that has
+    /// the most votes, ties going to the one proposed first.
+    pub fn votes(&self, bip300: &Bip300) -> Result<Vec<Vote>> {
+        let mut votes = vec![];
+        for sidechain in bip300.get_sidechains(None)? {
+            let bundles = bip300.get_bundles(sidechain.sidechain_number, None)?;
+            let leader = bundles.iter().reduce(|leader, bundle| {
+                if bundle.vote_count > leader.vote_count {
+                    bundle
+                } else {
+                    leader
+                }
+            });
+            let vote = match leader {
+                None => Vote::Abstain,
+                Some(leader) if self.denied_bundles.contains(&leader.bundle_txid) => {
+                    match self.denied_bundle_vote {
+                        DeniedBundleVote::Abstain => Vote::Abstain,
+                        DeniedBundleVote::Alarm => Vote::Alarm,
+                    }
+                }
+                Some(leader) => Vote::Upvote {
+                    bundle_txid: leader.bundle_txid,
+                },
+            };
+            votes.resize(sidechain.sidechain_number as usize, Vote::Abstain);
+            votes.push(vote);
+        }
+        while votes.last() == Some(&Vote::Abstain) {
+            votes.pop();
+        }
+        Ok(votes)
+    }
+
+    /// The smallest M4 casting the policy's votes, if there is anything to vote on.
+    pub fn ack_bundles(&self, bip300: &Bip300) -> Result<Option<M4AckBundles>> {
+        let votes = self.votes(bip300)?;
+        if votes.is_empty() {
+            return Ok(None);
+        }
+        smallest_ack_bundles(bip300, &votes).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip300_messages::{CoinbaseMessage, M4AckBundles, ABSTAIN_ONE_BYTE};
+
+    use super::*;
+    use crate::test_utils::{hash, TestChain};
+
+    fn policy(denied_bundles: &[Hash256], denied_bundle_vote: DeniedBundleVote) -> VotePolicy {
+        VotePolicy {
+            acked_sidechain_proposals: HashSet::new(),
+            denied_bundles: denied_bundles.iter().copied().collect(),
+            denied_bundle_vote,
+        }
+    }
+
+    /// Sidechains 0, 1 and 2, where sidechain 0 has bundles 1 and 2, the latter with a vote, and
+    /// sidechain 2 has bundle 3.
+    fn chain() -> Result<TestChain> {
+        let mut chain = TestChain::with_sidechains(&[0, 1, 2])?;
+        chain.connect(
+            [(0, 1), (0, 2), (2, 3)]
+                .map(|(sidechain_number, n)| CoinbaseMessage::M3ProposeBundle {
+                    sidechain_number,
+                    bundle_txid: hash(n),
+                })
+                .into(),
+            vec![],
+        )?;
+        chain.connect(
+            vec![CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte {
+                upvotes: vec![1],
+            })],
+            vec![],
+        )?;
+        Ok(chain)
+    }
+
+    #[test]
+    fn votes_upvote_the_leading_bundles() -> Result<()> {
+        let chain = chain()?;
+        let votes = policy(&[], DeniedBundleVote::Abstain).votes(&chain.bip300)?;
+        assert_eq!(
+            votes,
+            [
+                Vote::Upvote {
+                    bundle_txid: hash(2)
+                },
+                Vote::Abstain,
+                Vote::Upvote {
+                    bundle_txid: hash(3)
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn votes_on_denied_bundles_follow_the_policy() -> Result<()> {
+        let chain = chain()?;
+        let votes = policy(&[hash(2)], DeniedBundleVote::Alarm).votes(&chain.bip300)?;
+        assert_eq!(votes[0], Vote::Alarm);
+        let votes = policy(&[hash(3)], DeniedBundleVote::Abstain).votes(&chain.bip300)?;
+        assert_eq!(
+            votes,
+            [Vote::Upvote {
+                bundle_txid: hash(2)
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ack_bundles_repeats_matching_previous_votes() -> Result<()> {
+        let mut chain = chain()?;
+        let policy = policy(&[], DeniedBundleVote::Abstain);
+        let m4 = policy.ack_bundles(&chain.bip300)?;
+        assert!(
+            matches!(&m4, Some(M4AckBundles::OneByte { upvotes }) if *upvotes == [1, ABSTAIN_ONE_BYTE, 0]),
+            "{m4:?}"
+        );
+        chain.connect(vec![CoinbaseMessage::M4AckBundles(m4.unwrap())], vec![])?;
+        let m4 = policy.ack_bundles(&chain.bip300)?;
+        assert!(matches!(m4, Some(M4AckBundles::RepeatPrevious)), "{m4:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,960 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bip300_messages::{
+    sha256d, CoinbaseMessage, M4AckBundles, ABSTAIN_ONE_BYTE, ABSTAIN_TWO_BYTES, ALARM_ONE_BYTE,
+    ALARM_TWO_BYTES,
+};
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::raw::ProprietaryKey;
+use bitcoin::psbt::Psbt;
+use bitcoin::script::PushBytesBuf;
+use bitcoin::transaction::Version;
+use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use miette::{miette, IntoDiagnostic, Result};
+
+use crate::bip300::{drivechain_script, Bip300};
+use crate::policy::VotePolicy;
+use crate::server::bip300::get_coinbase_psbt_response::M4Encoding;
+use crate::server::bip300::{
+    AckBundles, AckBundlesEnum, GetCoinbasePsbtRequest, MessageIssue, MessageIssueSeverity,
+};
+use crate::types::{Ctip, Hash256, SidechainProposal, Vote};
+
+/// Prefix of the proprietary PSBT fields that identify BIP300 message outputs.
+pub const PROPRIETARY_PREFIX: &[u8] = b"bip300";
+
+/// Proprietary output field identifying the BIP300 message of an output. Its subtype is the
+/// number of the message, M1 to M4, and its value the sidechain number, empty for M4, which is
+/// about every sidechain.
+fn proprietary_key(message: &CoinbaseMessage) -> (ProprietaryKey, Vec<u8>) {
+    let (subtype, sidechain_number) = match message {
+        CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number, ..
+        } => (1, Some(*sidechain_number)),
+        CoinbaseMessage::M2AckSidechain {
+            sidechain_number, ..
+        } => (2, Some(*sidechain_number)),
+        CoinbaseMessage::M3ProposeBundle {
+            sidechain_number, ..
+        } => (3, Some(*sidechain_number)),
+        CoinbaseMessage::M4AckBundles(_) => (4, None),
+    };
+    let key = ProprietaryKey {
+        prefix: PROPRIETARY_PREFIX.to_vec(),
+        subtype,
+        key: vec![],
+    };
+    (key, sidechain_number.into_iter().collect())
+}
+
+/// A PSBT of a coinbase transaction paying only the BIP300 `messages`, for miners to merge into
+/// their coinbase.
+///
+/// The transaction has a single coinbase input, spending the null outpoint, and a zero value
+/// output per message.
+pub fn coinbase_psbt(messages: Vec<CoinbaseMessage>) -> Result<Psbt> {
+    let mut proprietary = vec![];
+    let mut output = vec![];
+    for message in messages {
+        proprietary.push(proprietary_key(&message));
+        output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: message.into(),
+        });
+    }
+    let transaction = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output,
+    };
+    let mut psbt = Psbt::from_unsigned_tx(transaction).into_diagnostic()?;
+    for (psbt_output, (key, value)) in psbt.outputs.iter_mut().zip(proprietary) {
+        psbt_output.proprietary.insert(key, value);
+    }
+    Ok(psbt)
+}
+
+/// Largest sidechain address that fits in an OP_RETURN output bitcoind relays.
+const MAX_SIDECHAIN_ADDRESS_LEN: usize = 80;
+
+/// A wallet output spent by a deposit. Only segwit outputs can be spent, since the PSBT carries
+/// just the output and not the transaction it is in.
+pub struct FundingInput {
+    pub outpoint: OutPoint,
+    /// The output itself, for computing the change and signing.
+    pub output: TxOut,
+}
+
+/// A PSBT of a deposit of `amount` to `sidechain_number`, spending its `ctip` and the wallet's
+/// `funding_inputs`.
+///
+/// The first output is the new ctip, holding the old ctip's value plus `amount`, and the second
+/// an OP_RETURN with the address the sidechain credits the deposit to. The ctip is spent by the
+/// first input, which needs no signature and is left finalized with an empty script sig. Whatever the funding inputs hold beyond `amount` and
+/// `fee` is paid to `change_script` in a third output, unless it is dust, which is left to the
+/// fee.
+pub fn deposit_psbt(
+    sidechain_number: u8,
+    ctip: &Ctip,
+    amount: Amount,
+    sidechain_address: &[u8],
+    funding_inputs: Vec<FundingInput>,
+    fee: Amount,
+    change_script: Option<ScriptBuf>,
+) -> Result<Psbt> {
+    if amount == Amount::ZERO {
+        return Err(miette!("deposit amount must be positive"));
+    }
+    if funding_inputs.is_empty() {
+        return Err(miette!("there are no funding inputs"));
+    }
+    if funding_inputs
+        .iter()
+        .any(|funding_input| funding_input.outpoint == ctip.outpoint)
+    {
+        return Err(miette!("the ctip can't be a funding input"));
+    }
+    if let Some(funding_input) = funding_inputs
+        .iter()
+        .find(|funding_input| !funding_input.output.script_pubkey.is_witness_program())
+    {
+        return Err(miette!(
+            "funding input {} is not a segwit output",
+            funding_input.outpoint
+        ));
+    }
+    if sidechain_address.len() > MAX_SIDECHAIN_ADDRESS_LEN {
+        return Err(miette!(
+            "sidechain address is {} bytes, at most {MAX_SIDECHAIN_ADDRESS_LEN} fit in an OP_RETURN",
+            sidechain_address.len()
+        ));
+    }
+    let value = Amount::from_sat(ctip.value)
+        .checked_add(amount)
+        .filter(|value| *value <= Amount::MAX_MONEY)
+        .ok_or_else(|| miette!("deposit amount {amount} is too large"))?;
+    let funding = funding_inputs
+        .iter()
+        .try_fold(Amount::ZERO, |funding, funding_input| {
+            funding.checked_add(funding_input.output.value)
+        })
+        .ok_or_else(|| miette!("funding inputs hold more than the money supply"))?;
+    let change = amount
+        .checked_add(fee)
+        .and_then(|spent| funding.checked_sub(spent))
+        .ok_or_else(|| {
+            miette!("funding inputs hold {funding}, less than the amount {amount} and fee {fee}")
+        })?;
+    let sidechain_address = PushBytesBuf::try_from(sidechain_address.to_vec()).into_diagnostic()?;
+    let mut output = vec![
+        TxOut {
+            value,
+            script_pubkey: drivechain_script(sidechain_number),
+        },
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(&sidechain_address),
+        },
+    ];
+    match change_script {
+        Some(change_script) if change >= change_script.dust_value() => output.push(TxOut {
+            value: change,
+            script_pubkey: change_script,
+        }),
+        Some(_) => {}
+        None if change == Amount::ZERO => {}
+        None => {
+            return Err(miette!(
+                "funding inputs hold {change} beyond the amount and fee, set a change address"
+            ))
+        }
+    }
+    let spent_outputs: Vec<TxOut> = funding_inputs
+        .iter()
+        .map(|funding_input| funding_input.output.clone())
+        .collect();
+    let input = std::iter::once(ctip.outpoint)
+        .chain(
+            funding_inputs
+                .iter()
+                .map(|funding_input| funding_input.outpoint),
+        )
+        .map(|previous_output| TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        })
+        .collect();
+    let transaction = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    };
+    let mut psbt = Psbt::from_unsigned_tx(transaction).into_diagnostic()?;
+    // The ctip isn't a segwit output, so it can't get a witness utxo, and we don't have the
+    // transaction it is in for a non-witness utxo. It is spent by an empty script sig though.
+    psbt.inputs[0].final_script_sig = Some(ScriptBuf::new());
+    for (psbt_input, spent_output) in psbt.inputs[1..].iter_mut().zip(spent_outputs) {
+        psbt_input.witness_utxo = Some(spent_output);
+    }
+    Ok(psbt)
+}
+
+/// Collects the issues found with the messages of a GetCoinbasePSBT request.
+struct Issues {
+    issues: Vec<MessageIssue>,
+    drop_invalid: bool,
+}
+
+impl Issues {
+    fn warn(&mut self, message: &str, index: usize, description: String) {
+        self.issues.push(MessageIssue {
+            message: message.into(),
+            index: index as u32,
+            severity: MessageIssueSeverity::Warning.into(),
+            description,
+            dropped: false,
+        });
+    }
+
+    /// Records an error, returning whether the message is kept.
+    fn error(&mut self, message: &str, index: usize, description: String) -> bool {
+        self.push_error(message, index, description, self.drop_invalid)
+    }
+
+    /// Records an error with a message that can't be encoded, and so is always dropped.
+    fn malformed(&mut self, message: &str, index: usize, description: String) {
+        self.push_error(message, index, description, true);
+    }
+
+    fn push_error(
+        &mut self,
+        message: &str,
+        index: usize,
+        description: String,
+        dropped: bool,
+    ) -> bool {
+        self.issues.push(MessageIssue {
+            message: message.into(),
+            index: index as u32,
+            severity: MessageIssueSeverity::Error.into(),
+            description,
+            dropped,
+        });
+        !dropped
+    }
+}
+
+/// Builds the messages of a GetCoinbasePSBT request and checks them against the current state of
+/// `bip300`, so that miners don't spend coinbase space on messages that would be ignored or make
+/// their block invalid.
+///
+/// Messages that can't be encoded are always left out, and messages with other errors are left
+/// out if the request asks for it. Every issue found is returned along with the messages. The
+/// messages of `vote_policy` are added if the request asks for them.
+pub fn requested_messages(
+    bip300: &Bip300,
+    vote_policy: &VotePolicy,
+    request: &GetCoinbasePsbtRequest,
+) -> Result<(Vec<CoinbaseMessage>, Vec<MessageIssue>)> {
+    let mut issues = Issues {
+        issues: vec![],
+        drop_invalid: request.drop_invalid,
+    };
+    let proposals: HashMap<Hash256, SidechainProposal> =
+        bip300.get_sidechain_proposals(None)?.into_iter().collect();
+    let sidechains: BTreeSet<u8> = bip300
+        .get_sidechains(None)?
+        .into_iter()
+        .map(|sidechain| sidechain.sidechain_number)
+        .collect();
+    let mut messages = vec![];
+
+    for (index, propose_sidechain) in request.propose_sidechains.iter().enumerate() {
+        let Ok(sidechain_number) = u8::try_from(propose_sidechain.sidechain_number) else {
+            issues.malformed(
+                "M1",
+                index,
+                format!(
+                    "invalid sidechain number {}",
+                    propose_sidechain.sidechain_number
+                ),
+            );
+            continue;
+        };
+        if proposals.contains_key(&sha256d(&propose_sidechain.data)) {
+            let description = "the sidechain was already proposed, the proposal is ignored";
+            if !issues.error("M1", index, description.into()) {
+                continue;
+            }
+        } else if sidechains.contains(&sidechain_number) {
+            issues.warn(
+                "M1",
+                index,
+                format!(
+                    "sidechain {sidechain_number} is active, replacing it needs the higher \
+                     threshold of used slots"
+                ),
+            );
+        }
+        messages.push(CoinbaseMessage::M1ProposeSidechain {
+            sidechain_number,
+            data: propose_sidechain.data.clone(),
+        });
+    }
+
+    for (index, ack_sidechain) in request.ack_sidechains.iter().enumerate() {
+        let Ok(sidechain_number) = u8::try_from(ack_sidechain.sidechain_number) else {
+            issues.malformed(
+                "M2",
+                index,
+                format!(
+                    "invalid sidechain number {}",
+                    ack_sidechain.sidechain_number
+                ),
+            );
+            continue;
+        };
+        let Ok(data_hash) = Hash256::try_from(ack_sidechain.data_hash.as_slice()) else {
+            issues.malformed("M2", index, "data hash must be 32 bytes".into());
+            continue;
+        };
+        let error = match proposals.get(&data_hash) {
+            None => Some("there is no pending proposal with this data hash".to_owned()),
+            Some(proposal) if proposal.sidechain_number != sidechain_number => Some(format!(
+                "the proposal is for sidechain {}",
+                proposal.sidechain_number
+            )),
+            Some(_) => None,
+        };
+        if let Some(error) = error {
+            if !issues.error("M2", index, format!("{error}, the ack is ignored")) {
+                continue;
+            }
+        }
+        messages.push(CoinbaseMessage::M2AckSidechain {
+            sidechain_number,
+            data_hash,
+        });
+    }
+    if request.apply_vote_policy {
+        for (sidechain_number, data_hash) in vote_policy.ack_sidechains(bip300)? {
+            let requested = messages.iter().any(|message| {
+                matches!(
+                    message,
+                    CoinbaseMessage::M2AckSidechain { data_hash: requested, .. }
+                        if *requested == data_hash
+                )
+            });
+            if !requested {
+                messages.push(CoinbaseMessage::M2AckSidechain {
+                    sidechain_number,
+                    data_hash,
+                });
+            }
+        }
+    }
+
+    for (index, propose_bundle) in request.propose_bundles.iter().enumerate() {
+        let Ok(sidechain_number) = u8::try_from(propose_bundle.sidechain_number) else {
+            issues.malformed(
+                "M3",
+                index,
+                format!(
+                    "invalid sidechain number {}",
+                    propose_bundle.sidechain_number
+                ),
+            );
+            continue;
+        };
+        let Ok(bundle_txid) = Hash256::try_from(propose_bundle.bundle_txid.as_slice()) else {
+            issues.malformed("M3", index, "bundle txid must be 32 bytes".into());
+            continue;
+        };
+        let error = if !sidechains.contains(&sidechain_number) {
+            Some(format!("sidechain {sidechain_number} isn't active"))
+        } else if bip300
+            .get_bundles(sidechain_number, None)?
+            .iter()
+            .any(|bundle| bundle.bundle_txid == bundle_txid)
+        {
+            Some("the bundle was already proposed".to_owned())
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            if !issues.error("M3", index, format!("{error}, the proposal is ignored")) {
+                continue;
+            }
+        }
+        messages.push(CoinbaseMessage::M3ProposeBundle {
+            sidechain_number,
+            bundle_txid,
+        });
+    }
+
+    if let Some(ack_bundles) = &request.ack_bundles {
+        if let Some(m4) = ack_bundles_message(bip300, ack_bundles, &sidechains, &mut issues)? {
+            messages.push(CoinbaseMessage::M4AckBundles(m4));
+        }
+        if request.apply_vote_policy {
+            issues.warn(
+                "M4",
+                0,
+                "ack_bundles is set, the vote policy's M4 is left out".into(),
+            );
+        }
+    } else if request.apply_vote_policy {
+        if let Some(m4) = vote_policy.ack_bundles(bip300)? {
+            messages.push(CoinbaseMessage::M4AckBundles(m4));
+        }
+    }
+
+    Ok((messages, issues.issues))
+}
+
+fn ack_bundles_message(
+    bip300: &Bip300,
+    ack_bundles: &AckBundles,
+    sidechains: &BTreeSet<u8>,
+    issues: &mut Issues,
+) -> Result<Option<M4AckBundles>> {
+    let upvotes = match ack_bundles.tag() {
+        AckBundlesEnum::RepeatPrevious => return Ok(Some(M4AckBundles::RepeatPrevious)),
+        AckBundlesEnum::LeadingBy50 => return Ok(Some(M4AckBundles::LeadingBy50)),
+        AckBundlesEnum::Upvotes => &ack_bundles.upvotes,
+    };
+    let Ok(upvotes) = upvotes
+        .iter()
+        .map(|upvote| u16::try_from(*upvote))
+        .collect::<Result<Vec<u16>, _>>()
+    else {
+        issues.malformed("M4", 0, "upvotes must fit in two bytes".into());
+        return Ok(None);
+    };
+    let two_bytes = upvotes.iter().any(|upvote| *upvote > u8::MAX as u16);
+    let (abstain, alarm) = if two_bytes {
+        (ABSTAIN_TWO_BYTES, ALARM_TWO_BYTES)
+    } else {
+        (ABSTAIN_ONE_BYTE as u16, ALARM_ONE_BYTE as u16)
+    };
+
+    // Votes are indexed by sidechain number, so there is one for every slot up to the last
+    // active sidechain.
+    let expected_len = sidechains.last().map_or(0, |last| *last as usize + 1);
+    let mut errors = vec![];
+    if upvotes.len() != expected_len {
+        errors.push(format!(
+            "there are {} votes, expected {expected_len} for the active sidechains",
+            upvotes.len()
+        ));
+    }
+    for (sidechain_number, vote) in upvotes.iter().enumerate() {
+        if *vote == abstain {
+            continue;
+        }
+        let Ok(sidechain_number) = u8::try_from(sidechain_number) else {
+            errors.push(format!(
+                "vote {sidechain_number} is past the last sidechain"
+            ));
+            break;
+        };
+        if !sidechains.contains(&sidechain_number) {
+            errors.push(format!("sidechain {sidechain_number} isn't active"));
+        } else if *vote != alarm {
+            let bundles = bip300.get_bundles(sidechain_number, None)?.len();
+            if *vote as usize >= bundles {
+                errors.push(format!(
+                    "sidechain {sidechain_number} has no bundle {vote}, it has {bundles}"
+                ));
+            }
+        }
+    }
+    let valid = errors.is_empty();
+    let mut keep = true;
+    for error in errors {
+        keep &= issues.error("M4", 0, error);
+    }
+    if !keep {
+        return Ok(None);
+    }
+    let m4 = if two_bytes {
+        M4AckBundles::TwoBytes { upvotes }
+    } else {
+        M4AckBundles::OneByte {
+            upvotes: upvotes.into_iter().map(|upvote| upvote as u8).collect(),
+        }
+    };
+    if !valid {
+        // Invalid votes are kept as they were requested.
+        return Ok(Some(m4));
+    }
+    let votes = bip300.resolve_votes(&m4)?;
+    smallest_ack_bundles(bip300, &votes).map(Some)
+}
+
+/// How the M4 of a GetCoinbasePSBT response is encoded.
+pub fn m4_encoding(m4: &M4AckBundles) -> M4Encoding {
+    match m4 {
+        M4AckBundles::RepeatPrevious => M4Encoding::RepeatPrevious,
+        M4AckBundles::LeadingBy50 => M4Encoding::LeadingBy50,
+        M4AckBundles::OneByte { .. } => M4Encoding::OneByte,
+        M4AckBundles::TwoBytes { .. } => M4Encoding::TwoBytes,
+    }
+}
+
+/// The smallest M4 casting `votes`, which are indexed by sidechain number and may only upvote
+/// existing bundles.
+///
+/// RepeatPrevious and LeadingBy50 take no space beyond the message tag, so they are used if they
+/// cast the same votes. Otherwise the upvote vector covers every active sidechain, with one byte
+/// per vote unless a bundle index doesn't fit.
+pub fn smallest_ack_bundles(bip300: &Bip300, votes: &[Vote]) -> Result<M4AckBundles> {
+    let mut votes = votes.to_vec();
+    while votes.last() == Some(&Vote::Abstain) {
+        votes.pop();
+    }
+    for m4 in [M4AckBundles::RepeatPrevious, M4AckBundles::LeadingBy50] {
+        if bip300.resolve_votes(&m4)? == votes {
+            return Ok(m4);
+        }
+    }
+    let len = bip300
+        .get_sidechains(None)?
+        .iter()
+        .map(|sidechain| sidechain.sidechain_number as usize + 1)
+        .max()
+        .unwrap_or(0)
+        .max(votes.len());
+    let mut upvotes = vec![];
+    for sidechain_number in 0..len {
+        let vote = votes.get(sidechain_number).unwrap_or(&Vote::Abstain);
+        let upvote = match vote {
+            Vote::Abstain => ABSTAIN_TWO_BYTES,
+            Vote::Alarm => ALARM_TWO_BYTES,
+            Vote::Upvote { bundle_txid } => {
+                let bundle_index = bip300
+                    .get_bundles(sidechain_number as u8, None)?
+                    .iter()
+                    .position(|bundle| bundle.bundle_txid == *bundle_txid)
+                    .ok_or_else(|| {
+                        miette!(
+                            "sidechain {sidechain_number} has no bundle {}",
+                            Txid::from_byte_array(*bundle_txid)
+                        )
+                    })?;
+                match u16::try_from(bundle_index) {
+                    Ok(bundle_index) if bundle_index < ALARM_TWO_BYTES => bundle_index,
+                    _ => return Err(miette!("bundle index {bundle_index} doesn't fit in an M4")),
+                }
+            }
+        };
+        upvotes.push(upvote);
+    }
+    // Alarm and abstain are the two highest values of either width.
+    let one_byte = upvotes
+        .iter()
+        .all(|upvote| *upvote >= ALARM_TWO_BYTES || *upvote < ALARM_ONE_BYTE as u16);
+    let m4 = if one_byte {
+        M4AckBundles::OneByte {
+            upvotes: upvotes
+                .into_iter()
+                .map(|upvote| match upvote {
+                    ABSTAIN_TWO_BYTES => ABSTAIN_ONE_BYTE,
+                    ALARM_TWO_BYTES => ALARM_ONE_BYTE,
+                    bundle_index => bundle_index as u8,
+                })
+                .collect(),
+        }
+    } else {
+        M4AckBundles::TwoBytes { upvotes }
+    };
+    Ok(m4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bip300::message_name;
+    use crate::server::bip300::{AckSidechain, ProposeBundle, ProposeSidechain};
+    use crate::test_utils::{ctip, hash, sidechain, TestChain};
+    use crate::types::InitialState;
+
+    /// Sidechain 0 with bundle 1, and a proposal of sidechain 1 with data 1.
+    fn chain() -> Result<TestChain> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        chain.connect(
+            vec![
+                CoinbaseMessage::M1ProposeSidechain {
+                    sidechain_number: 1,
+                    data: vec![1],
+                },
+                CoinbaseMessage::M3ProposeBundle {
+                    sidechain_number: 0,
+                    bundle_txid: hash(1),
+                },
+            ],
+            vec![],
+        )?;
+        Ok(chain)
+    }
+
+    fn upvotes(upvotes: &[u32]) -> AckBundles {
+        AckBundles {
+            tag: AckBundlesEnum::Upvotes.into(),
+            upvotes: upvotes.to_vec(),
+        }
+    }
+
+    /// A request with one message of every kind, all of them invalid in `chain()`.
+    fn invalid_request() -> GetCoinbasePsbtRequest {
+        GetCoinbasePsbtRequest {
+            // Already proposed.
+            propose_sidechains: vec![ProposeSidechain {
+                sidechain_number: 1,
+                data: vec![1],
+            }],
+            // Not proposed.
+            ack_sidechains: vec![AckSidechain {
+                sidechain_number: 1,
+                data_hash: hash(2).to_vec(),
+            }],
+            // Not active.
+            propose_bundles: vec![ProposeBundle {
+                sidechain_number: 2,
+                bundle_txid: hash(2).to_vec(),
+            }],
+            // Sidechain 0 has a single bundle.
+            ack_bundles: Some(upvotes(&[1])),
+            ..GetCoinbasePsbtRequest::default()
+        }
+    }
+
+    fn names(messages: &[CoinbaseMessage]) -> Vec<&'static str> {
+        messages.iter().map(message_name).collect()
+    }
+
+    fn messages() -> Vec<CoinbaseMessage> {
+        vec![
+            CoinbaseMessage::M1ProposeSidechain {
+                sidechain_number: 1,
+                data: vec![1, 2, 3],
+            },
+            CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious),
+        ]
+    }
+
+    #[test]
+    fn coinbase_psbt_has_an_output_per_message() -> Result<()> {
+        let psbt = coinbase_psbt(messages())?;
+        // The PSBT survives being serialized, as it is sent to miners.
+        let psbt = Psbt::deserialize(&psbt.serialize()).into_diagnostic()?;
+
+        let transaction = &psbt.unsigned_tx;
+        assert_eq!(transaction.input.len(), 1);
+        assert!(transaction.input[0].previous_output.is_null());
+        assert_eq!(transaction.output.len(), messages().len());
+        for ((output, psbt_output), message) in
+            transaction.output.iter().zip(&psbt.outputs).zip(messages())
+        {
+            assert_eq!(output.value, Amount::ZERO);
+            let (key, value) = proprietary_key(&message);
+            assert_eq!(psbt_output.proprietary.get(&key), Some(&value));
+            let script_pubkey: ScriptBuf = message.into();
+            assert_eq!(output.script_pubkey, script_pubkey);
+        }
+        // M1 is about sidechain 1, M4 about every sidechain.
+        assert_eq!(psbt.outputs[0].proprietary.values().next(), Some(&vec![1]));
+        assert_eq!(psbt.outputs[1].proprietary.values().next(), Some(&vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn valid_messages_have_no_issues() -> Result<()> {
+        let chain = chain()?;
+        let request = GetCoinbasePsbtRequest {
+            propose_sidechains: vec![ProposeSidechain {
+                sidechain_number: 2,
+                data: vec![2],
+            }],
+            ack_sidechains: vec![AckSidechain {
+                sidechain_number: 1,
+                data_hash: sha256d(&[1]).to_vec(),
+            }],
+            propose_bundles: vec![ProposeBundle {
+                sidechain_number: 0,
+                bundle_txid: hash(2).to_vec(),
+            }],
+            ack_bundles: Some(upvotes(&[0])),
+            ..GetCoinbasePsbtRequest::default()
+        };
+        let (messages, issues) =
+            requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert_eq!(names(&messages), ["M1", "M2", "M3", "M4"]);
+        assert_eq!(issues, []);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_messages_are_kept_unless_dropped() -> Result<()> {
+        let chain = chain()?;
+        let mut request = invalid_request();
+        let (messages, issues) =
+            requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert_eq!(names(&messages), ["M1", "M2", "M3", "M4"]);
+        let issue_messages: Vec<_> = issues.iter().map(|issue| issue.message.as_str()).collect();
+        assert_eq!(issue_messages, ["M1", "M2", "M3", "M4"]);
+        assert!(issues
+            .iter()
+            .all(|issue| { issue.severity() == MessageIssueSeverity::Error && !issue.dropped }));
+
+        request.drop_invalid = true;
+        let (messages, issues) =
+            requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert_eq!(names(&messages), Vec::<&str>::new());
+        assert_eq!(issues.len(), 4);
+        assert!(issues.iter().all(|issue| issue.dropped));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_messages_are_always_dropped() -> Result<()> {
+        let chain = chain()?;
+        let request = GetCoinbasePsbtRequest {
+            propose_sidechains: vec![ProposeSidechain {
+                sidechain_number: 256,
+                data: vec![2],
+            }],
+            ack_sidechains: vec![AckSidechain {
+                sidechain_number: 1,
+                data_hash: vec![1; 31],
+            }],
+            propose_bundles: vec![ProposeBundle {
+                sidechain_number: 0,
+                bundle_txid: vec![],
+            }],
+            ack_bundles: Some(upvotes(&[1 << 16])),
+            ..GetCoinbasePsbtRequest::default()
+        };
+        let (messages, issues) =
+            requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert_eq!(names(&messages), Vec::<&str>::new());
+        assert_eq!(issues.len(), 4);
+        assert!(issues.iter().all(|issue| issue.dropped));
+        Ok(())
+    }
+
+    #[test]
+    fn active_sidechains_can_be_replaced_with_a_warning() -> Result<()> {
+        let chain = chain()?;
+        let request = GetCoinbasePsbtRequest {
+            propose_sidechains: vec![ProposeSidechain {
+                sidechain_number: 0,
+                data: vec![2],
+            }],
+            drop_invalid: true,
+            ..GetCoinbasePsbtRequest::default()
+        };
+        let (messages, issues) =
+            requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert_eq!(names(&messages), ["M1"]);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity(), MessageIssueSeverity::Warning);
+        Ok(())
+    }
+
+    fn ack_bundles(upvotes: Vec<u8>) -> CoinbaseMessage {
+        CoinbaseMessage::M4AckBundles(M4AckBundles::OneByte { upvotes })
+    }
+
+    #[test]
+    fn smallest_ack_bundles_repeats_previous_votes() -> Result<()> {
+        let mut chain = chain()?;
+        chain.connect(vec![ack_bundles(vec![0])], vec![])?;
+        let votes = [Vote::Upvote {
+            bundle_txid: hash(1),
+        }];
+        let m4 = smallest_ack_bundles(&chain.bip300, &votes)?;
+        assert_eq!(m4_encoding(&m4), M4Encoding::RepeatPrevious);
+        Ok(())
+    }
+
+    #[test]
+    fn smallest_ack_bundles_abstains_with_leading_by_50_without_leaders() -> Result<()> {
+        let mut chain = chain()?;
+        chain.connect(vec![ack_bundles(vec![ALARM_ONE_BYTE])], vec![])?;
+        let m4 = smallest_ack_bundles(&chain.bip300, &[Vote::Abstain])?;
+        assert_eq!(m4_encoding(&m4), M4Encoding::LeadingBy50);
+        Ok(())
+    }
+
+    #[test]
+    fn smallest_ack_bundles_uses_one_byte_when_indexes_fit() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[0, 2])?;
+        let votes = [Vote::Alarm];
+        let m4 = smallest_ack_bundles(&chain.bip300, &votes)?;
+        // Every active sidechain gets a vote.
+        assert!(matches!(
+            m4,
+            M4AckBundles::OneByte { upvotes }
+                if upvotes == [ALARM_ONE_BYTE, ABSTAIN_ONE_BYTE, ABSTAIN_ONE_BYTE]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn smallest_ack_bundles_uses_two_bytes_for_large_indexes() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[0])?;
+        let bundle_count = ALARM_ONE_BYTE as u16 + 1;
+        let proposals = (0..bundle_count)
+            .map(|n| {
+                let mut bundle_txid = [0; 32];
+                bundle_txid[..2].copy_from_slice(&n.to_be_bytes());
+                CoinbaseMessage::M3ProposeBundle {
+                    sidechain_number: 0,
+                    bundle_txid,
+                }
+            })
+            .collect();
+        chain.connect(proposals, vec![])?;
+        let last_bundle = chain.bip300.get_bundles(0, None)?.pop().unwrap();
+        let votes = [Vote::Upvote {
+            bundle_txid: last_bundle.bundle_txid,
+        }];
+        let m4 = smallest_ack_bundles(&chain.bip300, &votes)?;
+        assert!(matches!(
+            m4,
+            M4AckBundles::TwoBytes { upvotes } if upvotes == [bundle_count - 1]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn smallest_ack_bundles_rejects_unknown_bundles() -> Result<()> {
+        let chain = chain()?;
+        let votes = [Vote::Upvote {
+            bundle_txid: hash(2),
+        }];
+        assert!(smallest_ack_bundles(&chain.bip300, &votes).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn requested_upvotes_are_sent_as_the_smallest_m4() -> Result<()> {
+        let mut chain = chain()?;
+        chain.connect(vec![ack_bundles(vec![0])], vec![])?;
+        let request = GetCoinbasePsbtRequest {
+            ack_bundles: Some(upvotes(&[0])),
+            ..GetCoinbasePsbtRequest::default()
+        };
+        let (messages, _) = requested_messages(&chain.bip300, &VotePolicy::default(), &request)?;
+        assert!(matches!(
+            messages[..],
+            [CoinbaseMessage::M4AckBundles(M4AckBundles::RepeatPrevious)]
+        ));
+        Ok(())
+    }
+
+    /// A P2WPKH wallet output of `value`.
+    fn funding_input(n: u8, value: u64) -> FundingInput {
+        FundingInput {
+            outpoint: OutPoint {
+                txid: Txid::from_byte_array(hash(n)),
+                vout: 1,
+            },
+            output: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: wallet_script(),
+            },
+        }
+    }
+
+    fn wallet_script() -> ScriptBuf {
+        ScriptBuf::from_bytes([vec![0x00, 0x14], vec![1; 20]].concat())
+    }
+
+    fn deposit(
+        funding_inputs: Vec<FundingInput>,
+        change_script: Option<ScriptBuf>,
+    ) -> Result<Psbt> {
+        deposit_psbt(
+            0,
+            &ctip(1, 1000),
+            Amount::from_sat(500),
+            b"address",
+            funding_inputs,
+            Amount::from_sat(100),
+            change_script,
+        )
+    }
+
+    #[test]
+    fn deposit_psbt_pays_the_change() -> Result<()> {
+        let psbt = deposit(vec![funding_input(2, 2000)], Some(wallet_script()))?;
+        let transaction = &psbt.unsigned_tx;
+        let outputs: Vec<_> = transaction
+            .output
+            .iter()
+            .map(|output| output.value.to_sat())
+            .collect();
+        assert_eq!(outputs, [1500, 0, 1400]);
+        assert_eq!(transaction.output[2].script_pubkey, wallet_script());
+        assert_eq!(psbt.inputs[0].witness_utxo, None);
+        assert_eq!(psbt.inputs[0].final_script_sig, Some(ScriptBuf::new()));
+        assert_eq!(
+            psbt.inputs[1].witness_utxo,
+            Some(funding_input(2, 2000).output)
+        );
+
+        // Once signed, the transaction is a deposit.
+        let mut chain = TestChain::new(InitialState {
+            sidechains: vec![sidechain(0)],
+            ctips: [(0, ctip(1, 1000))].into(),
+        })?;
+        chain.connect(vec![], vec![transaction.clone()])?;
+        let new_ctip = chain.bip300.get_ctip(0, None)?.unwrap();
+        assert_eq!(new_ctip.outpoint.txid, transaction.txid());
+        assert_eq!(new_ctip.value, 1500);
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_psbt_leaves_dust_to_the_fee() -> Result<()> {
+        let psbt = deposit(vec![funding_input(2, 700)], Some(wallet_script()))?;
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_psbt_needs_a_change_address_for_change() -> Result<()> {
+        assert!(deposit(vec![funding_input(2, 2000)], None).is_err());
+        let psbt = deposit(vec![funding_input(2, 300), funding_input(3, 300)], None)?;
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn deposit_psbt_rejects_invalid_funding() {
+        // Less than the amount and the fee.
+        assert!(deposit(vec![funding_input(2, 599)], Some(wallet_script())).is_err());
+        // The ctip is already spent by the first input.
+        let mut spends_ctip = funding_input(1, 2000);
+        spends_ctip.outpoint = ctip(1, 1000).outpoint;
+        assert!(deposit(vec![spends_ctip], Some(wallet_script())).is_err());
+        assert!(deposit(vec![], Some(wallet_script())).is_err());
+        // P2PKH, which would need the whole transaction it is in.
+        let mut p2pkh = funding_input(2, 2000);
+        p2pkh.output.script_pubkey =
+            ScriptBuf::from_bytes([vec![0x76, 0xa9, 0x14], vec![1; 20], vec![0x88, 0xac]].concat());
+        assert!(deposit(vec![p2pkh], Some(wallet_script())).is_err());
+    }
+}
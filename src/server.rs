@@ -1,28 +1,95 @@
 use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use bitcoin::absolute::Height;
-use bitcoin::consensus::{Decodable, Encodable};
-use bitcoin::transaction::Version;
-use bitcoin::{Amount, Block, Transaction, TxOut};
+use bip300_messages::CoinbaseMessage;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Amount, Block, OutPoint, ScriptBuf, TxOut, Txid};
 use miette::Result;
-use tonic::{Request, Response, Status};
+use tokio::sync::{broadcast::error::RecvError, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::info;
 
 use bip300::validator_server::Validator;
-use bip300::{ConnectBlockRequest, ConnectBlockResponse};
+use bip300::SubscribeEventsRequest;
+use bip300::{ConnectBlockRequest, ConnectBlockResponse, ConnectBlocksResponse};
 use bip300::{DisconnectBlockRequest, DisconnectBlockResponse};
+use bip300::{GetBlockInfoRequest, GetBlockInfoResponse};
+use bip300::{GetBundlesRequest, GetBundlesResponse};
+use bip300::{GetChainInfoRequest, GetChainInfoResponse};
+use bip300::{GetCtipRequest, GetCtipResponse};
+use bip300::{GetSidechainProposalsRequest, GetSidechainProposalsResponse};
+use bip300::{GetSidechainsRequest, GetSidechainsResponse};
 use bip300::{IsValidRequest, IsValidResponse};
 
+use crate::auth::has_write_scope;
 pub use crate::bip300::Bip300;
+use crate::bip300::SCHEMA_VERSION;
+use crate::decode::decode;
+use crate::policy::VotePolicy;
+use crate::psbt::{coinbase_psbt, deposit_psbt, m4_encoding, requested_messages, FundingInput};
+use crate::types::{Bundle, ConsensusParams, Ctip, Event, Hash256, Sidechain, SidechainProposal};
 
-use self::bip300::{AckBundlesEnum, GetCoinbasePsbtRequest, GetCoinbasePsbtResponse};
-use bip300_messages::{CoinbaseMessage, M4AckBundles};
+use self::bip300::{CreateDepositTransactionRequest, CreateDepositTransactionResponse};
+use self::bip300::{DecodeBip300MessagesRequest, DecodeBip300MessagesResponse};
+use self::bip300::{GetCoinbasePsbtRequest, GetCoinbasePsbtResponse};
 
 pub mod bip300 {
     tonic::include_proto!("validator");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("validator_descriptor");
+}
+
+const EVENTS_STREAM_CAPACITY: usize = 256;
+
+pub const DEFAULT_CONNECT_BLOCKS_BATCH_SIZE: usize = 1000;
+
+pub struct ValidatorService {
+    bip300: Arc<Bip300>,
+    vote_policy: VotePolicy,
+    connect_blocks_batch_size: usize,
+    /// Set to true when the monitor is shutting down, which ends event streams.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ValidatorService {
+    pub fn new(
+        bip300: Arc<Bip300>,
+        vote_policy: VotePolicy,
+        connect_blocks_batch_size: usize,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            bip300,
+            vote_policy,
+            connect_blocks_batch_size,
+            shutdown,
+        }
+    }
+
+    /// Connects `batch`, returning the number of blocks connected, the height of the last one and
+    /// the height and error of the block that failed.
+    async fn connect_batch(
+        &self,
+        batch: Vec<(u32, Block)>,
+    ) -> Result<(u32, Option<u32>, Option<(u32, String)>), Status> {
+        let bip300 = self.bip300.clone();
+        let connected = tokio::task::spawn_blocking(move || bip300.connect_blocks(&batch))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let failure = connected
+            .failure
+            .map(|(height, err)| (height, err.to_string()));
+        Ok((connected.count as u32, connected.last_height, failure))
+    }
 }
 
 #[tonic::async_trait]
-impl Validator for Bip300 {
+impl Validator for ValidatorService {
     async fn is_valid(
         &self,
         _request: Request<IsValidRequest>,
@@ -34,20 +101,104 @@ impl Validator for Bip300 {
         &self,
         request: Request<ConnectBlockRequest>,
     ) -> Result<Response<ConnectBlockResponse>, Status> {
-        // println!("REQUEST = {:?}", request);
+        if !has_write_scope(&request) {
+            return Err(Status::permission_denied("the write scope is required"));
+        }
         let request = request.into_inner();
         let mut cursor = Cursor::new(request.block);
-        let block = Block::consensus_decode(&mut cursor).unwrap();
-        self.connect_block(&block, request.height).unwrap();
+        let block = Block::consensus_decode(&mut cursor)
+            .map_err(|err| Status::invalid_argument(format!("failed to decode block: {err}")))?;
+        // Rejected blocks fail the precondition, errors committing them are internal.
+        let (_, _, failure) = self.connect_batch(vec![(request.height, block)]).await?;
+        if let Some((_, error)) = failure {
+            return Err(Status::failed_precondition(error));
+        }
         let response = ConnectBlockResponse {};
         Ok(Response::new(response))
     }
 
+    async fn connect_blocks(
+        &self,
+        request: Request<Streaming<ConnectBlockRequest>>,
+    ) -> Result<Response<ConnectBlocksResponse>, Status> {
+        if !has_write_scope(&request) {
+            return Err(Status::permission_denied("the write scope is required"));
+        }
+        let mut stream = request.into_inner();
+        let mut blocks_connected = 0;
+        let mut last_connected_height = None;
+        let mut batch = vec![];
+        let mut failure = None;
+        while let Some(request) = stream.message().await? {
+            let mut cursor = Cursor::new(request.block);
+            let block = match Block::consensus_decode(&mut cursor) {
+                Ok(block) => block,
+                Err(err) => {
+                    failure = Some((request.height, format!("failed to decode block: {err}")));
+                    break;
+                }
+            };
+            batch.push((request.height, block));
+            if batch.len() >= self.connect_blocks_batch_size {
+                let (connected, last_height, batch_failure) =
+                    self.connect_batch(std::mem::take(&mut batch)).await?;
+                blocks_connected += connected;
+                last_connected_height = last_height.or(last_connected_height);
+                if batch_failure.is_some() {
+                    failure = batch_failure;
+                    break;
+                }
+                info!(blocks_connected, height = last_height, "connected blocks");
+            }
+        }
+        if !batch.is_empty() {
+            // Blocks received before a block that failed to decode are still connected.
+            let (connected, last_height, batch_failure) = self.connect_batch(batch).await?;
+            blocks_connected += connected;
+            last_connected_height = last_height.or(last_connected_height);
+            if batch_failure.is_some() {
+                failure = batch_failure;
+            }
+        }
+        let tip = self
+            .bip300
+            .get_chain_tip()
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map(|(height, block_info)| bip300::ChainTip {
+                height,
+                block_hash: block_info.block_hash.to_vec(),
+                state_commitment: block_info.state_commitment.to_vec(),
+            });
+        let (failed_height, error) = match failure {
+            Some((height, error)) => (Some(height), error),
+            None => (None, String::new()),
+        };
+        let response = ConnectBlocksResponse {
+            blocks_connected,
+            last_connected_height,
+            tip,
+            failed_height,
+            error,
+        };
+        Ok(Response::new(response))
+    }
+
     async fn disconnect_block(
         &self,
-        _request: Request<DisconnectBlockRequest>,
+        request: Request<DisconnectBlockRequest>,
     ) -> Result<Response<DisconnectBlockResponse>, Status> {
-        todo!();
+        if !has_write_scope(&request) {
+            return Err(Status::permission_denied("the write scope is required"));
+        }
+        let request = request.into_inner();
+        let mut cursor = Cursor::new(request.block);
+        let block = Block::consensus_decode(&mut cursor)
+            .map_err(|err| Status::invalid_argument(format!("failed to decode block: {err}")))?;
+        self.bip300
+            .disconnect_block(&block)
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+        let response = DisconnectBlockResponse {};
+        Ok(Response::new(response))
     }
 
     async fn get_coinbase_psbt(
@@ -55,86 +206,617 @@ impl Validator for Bip300 {
         request: Request<GetCoinbasePsbtRequest>,
     ) -> Result<Response<GetCoinbasePsbtResponse>, Status> {
         let request = request.into_inner();
-        let mut messages = vec![];
-        for propose_sidechain in &request.propose_sidechains {
-            let sidechain_number = propose_sidechain.sidechain_number as u8;
-            let data = propose_sidechain.data.clone();
-            let message = CoinbaseMessage::M1ProposeSidechain {
-                sidechain_number,
-                data,
-            };
-            messages.push(message);
+        let (messages, issues) = requested_messages(&self.bip300, &self.vote_policy, &request)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if !request.drop_invalid {
+            // Without drop_invalid, only messages that can't be encoded are left out.
+            if let Some(issue) = issues.iter().find(|issue| issue.dropped) {
+                return Err(Status::invalid_argument(format!(
+                    "{} {}: {}",
+                    issue.message, issue.index, issue.description
+                )));
+            }
         }
-        for ack_sidechain in &request.ack_sidechains {
-            let sidechain_number = ack_sidechain.sidechain_number as u8;
-            let data_hash: &[u8; 32] = ack_sidechain.data_hash.as_slice().try_into().unwrap();
-            let message = CoinbaseMessage::M2AckSidechain {
-                sidechain_number,
-                data_hash: data_hash.clone(),
-            };
-            messages.push(message);
+        let m4_encoding = messages.iter().find_map(|message| match message {
+            CoinbaseMessage::M4AckBundles(m4) => Some(m4_encoding(m4) as i32),
+            _ => None,
+        });
+        let psbt = coinbase_psbt(messages).map_err(|err| Status::internal(err.to_string()))?;
+        let psbt_base64 = if request.base64 {
+            psbt.to_string()
+        } else {
+            String::new()
+        };
+        let response = GetCoinbasePsbtResponse {
+            psbt: psbt.serialize(),
+            psbt_base64,
+            issues,
+            m4_encoding,
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn create_deposit_transaction(
+        &self,
+        request: Request<CreateDepositTransactionRequest>,
+    ) -> Result<Response<CreateDepositTransactionResponse>, Status> {
+        let request = request.into_inner();
+        let sidechain_number = parse_sidechain_number(request.sidechain_number)?;
+        let mut funding_inputs = vec![];
+        for funding_input in request.funding_inputs {
+            let outpoint = funding_input
+                .outpoint
+                .ok_or_else(|| Status::invalid_argument("funding input outpoint is missing"))?;
+            let txid = Txid::from_slice(&outpoint.txid)
+                .map_err(|_| Status::invalid_argument("funding input txid must be 32 bytes"))?;
+            funding_inputs.push(FundingInput {
+                outpoint: OutPoint {
+                    txid,
+                    vout: outpoint.vout,
+                },
+                output: TxOut {
+                    value: Amount::from_sat(funding_input.value),
+                    script_pubkey: ScriptBuf::from_bytes(funding_input.script_pubkey),
+                },
+            });
         }
-        for propose_bundle in &request.propose_bundles {
-            let sidechain_number = propose_bundle.sidechain_number as u8;
-            let bundle_txid: &[u8; 32] = &propose_bundle.bundle_txid.as_slice().try_into().unwrap();
-            let message = CoinbaseMessage::M3ProposeBundle {
-                sidechain_number,
-                bundle_txid: bundle_txid.clone(),
-            };
-            messages.push(message);
-        }
-        if let Some(ack_bundles) = &request.ack_bundles {
-            let message = match ack_bundles.tag() {
-                AckBundlesEnum::RepeatPrevious => M4AckBundles::RepeatPrevious,
-                AckBundlesEnum::LeadingBy50 => M4AckBundles::LeadingBy50,
-                AckBundlesEnum::Upvotes => {
-                    let mut two_bytes = false;
-                    for upvote in &ack_bundles.upvotes {
-                        if *upvote > u8::MAX as u32 {
-                            two_bytes = true;
-                        }
-                        if *upvote > u16::MAX as u32 {
-                            panic!("upvote too large");
-                        }
+        let change_script = if request.change_address.is_empty() {
+            None
+        } else {
+            let invalid_change_address =
+                |err: String| Status::invalid_argument(format!("invalid change address: {err}"));
+            let change_address = Address::from_str(&request.change_address)
+                .map_err(|err| invalid_change_address(err.to_string()))?
+                .require_network(self.bip300.network())
+                .map_err(|err| invalid_change_address(err.to_string()))?;
+            Some(change_address.script_pubkey())
+        };
+        let ctip = self
+            .bip300
+            .get_ctip(sidechain_number, None)
+            .map_err(|err| Status::internal(err.to_string()))?
+            .ok_or_else(|| {
+                Status::failed_precondition(format!("sidechain {sidechain_number} has no ctip"))
+            })?;
+        let psbt = deposit_psbt(
+            sidechain_number,
+            &ctip,
+            Amount::from_sat(request.amount),
+            request.sidechain_address.as_bytes(),
+            funding_inputs,
+            Amount::from_sat(request.fee),
+            change_script,
+        )
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let psbt_base64 = if request.base64 {
+            psbt.to_string()
+        } else {
+            String::new()
+        };
+        let response = CreateDepositTransactionResponse {
+            psbt: psbt.serialize(),
+            psbt_base64,
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn decode_bip300_messages(
+        &self,
+        request: Request<DecodeBip300MessagesRequest>,
+    ) -> Result<Response<DecodeBip300MessagesResponse>, Status> {
+        let decoded = decode(&request.into_inner().data)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let messages = decoded
+            .messages
+            .into_iter()
+            .map(|message| bip300::DecodedMessage {
+                vout: message.vout,
+                message: message.message.into(),
+                sidechain_number: message.sidechain_number.map(u32::from),
+                fields: message
+                    .fields
+                    .into_iter()
+                    .map(|(name, value)| (name.into(), value))
+                    .collect(),
+            })
+            .collect();
+        let drivechain_outputs = decoded
+            .drivechain_outputs
+            .into_iter()
+            .map(|output| bip300::DrivechainOutput {
+                txid: output.txid.to_string(),
+                vout: output.vout,
+                sidechain_number: output.sidechain_number.map(u32::from),
+                value: output.value,
+            })
+            .collect();
+        let response = DecodeBip300MessagesResponse {
+            messages,
+            drivechain_outputs,
+        };
+        Ok(Response::new(response))
+    }
+
+    type SubscribeEventsStream = ReceiverStream<Result<bip300::Event, Status>>;
+
+    async fn subscribe_events(
+        &self,
+        request: Request<SubscribeEventsRequest>,
+    ) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        let from_height = request.into_inner().from_height.unwrap_or(0);
+        // Subscribe before reading stored events, so that nothing connected in between is lost.
+        let mut live_events = self.bip300.subscribe_events();
+        let bip300 = self.bip300.clone();
+        let stored_events = tokio::task::spawn_blocking(move || bip300.get_events(from_height))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let (sender, receiver) = mpsc::channel(EVENTS_STREAM_CAPACITY);
+        let shutdown_sender = sender.clone();
+        let forward_events = async move {
+            let mut replayed_height = None;
+            for (height, events) in stored_events {
+                for event in events {
+                    if sender.send(Ok((height, event).into())).await.is_err() {
+                        return;
                     }
-                    if two_bytes {
-                        let upvotes = ack_bundles
-                            .upvotes
-                            .iter()
-                            .map(|upvote| upvote.clone().try_into().unwrap())
-                            .collect();
-                        M4AckBundles::TwoBytes { upvotes }
-                    } else {
-                        let upvotes = ack_bundles
-                            .upvotes
-                            .iter()
-                            .map(|upvote| upvote.clone().try_into().unwrap())
-                            .collect();
-                        M4AckBundles::OneByte { upvotes }
+                }
+                replayed_height = Some(height);
+            }
+            loop {
+                let (height, event) = match live_events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        let status = Status::data_loss(format!(
+                            "subscriber lagged behind by {skipped} events, resubscribe from the last received height"
+                        ));
+                        let _ = sender.send(Err(status)).await;
+                        return;
                     }
+                    Err(RecvError::Closed) => return,
+                };
+                if height < from_height {
+                    continue;
                 }
-            };
-            let message = CoinbaseMessage::M4AckBundles(message);
-            messages.push(message);
-        }
+                if let Event::BlockDisconnected { .. } = event {
+                    // Blocks at this height may be connected again and must not be skipped.
+                    replayed_height = replayed_height
+                        .map(|replayed_height: u32| replayed_height.min(height.saturating_sub(1)));
+                } else if replayed_height.is_some_and(|replayed_height| height <= replayed_height) {
+                    continue;
+                }
+                if sender.send(Ok((height, event).into())).await.is_err() {
+                    return;
+                }
+            }
+        };
+        let mut shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                () = forward_events => {}
+                // Open streams would keep the server from shutting down.
+                _ = shutdown.wait_for(|shutdown| *shutdown) => {
+                    let status = Status::unavailable("the validator is shutting down");
+                    let _ = shutdown_sender.try_send(Err(status));
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_chain_info(
+        &self,
+        _request: Request<GetChainInfoRequest>,
+    ) -> Result<Response<GetChainInfoResponse>, Status> {
+        let tip = self
+            .bip300
+            .get_chain_tip()
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map(|(height, block_info)| bip300::ChainTip {
+                height,
+                block_hash: block_info.block_hash.to_vec(),
+                state_commitment: block_info.state_commitment.to_vec(),
+            });
+        let response = GetChainInfoResponse {
+            tip,
+            network: self.bip300.network().to_string(),
+            consensus_params: Some(self.bip300.consensus_params().into()),
+            schema_version: SCHEMA_VERSION,
+            blocks_connected_since_startup: self.bip300.blocks_connected(),
+        };
+        Ok(Response::new(response))
+    }
 
-        let output = messages
+    async fn get_block_info(
+        &self,
+        request: Request<GetBlockInfoRequest>,
+    ) -> Result<Response<GetBlockInfoResponse>, Status> {
+        let request = request.into_inner();
+        let block_info = match request.height {
+            Some(height) => self
+                .bip300
+                .get_block_info(height)
+                .map_err(|err| Status::internal(err.to_string()))?
+                .map(|block_info| (height, block_info)),
+            None => self
+                .bip300
+                .get_chain_tip()
+                .map_err(|err| Status::internal(err.to_string()))?,
+        };
+        let Some((height, block_info)) = block_info else {
+            return Err(Status::not_found("no block connected at that height"));
+        };
+        let response = GetBlockInfoResponse {
+            height,
+            block_hash: block_info.block_hash.to_vec(),
+            prev_block_hash: block_info.prev_block_hash.to_vec(),
+            state_commitment: block_info.state_commitment.to_vec(),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn get_sidechain_proposals(
+        &self,
+        request: Request<GetSidechainProposalsRequest>,
+    ) -> Result<Response<GetSidechainProposalsResponse>, Status> {
+        let request = request.into_inner();
+        let sidechain_proposals = self
+            .bip300
+            .get_sidechain_proposals(request.at_height)
+            .map_err(|err| Status::internal(err.to_string()))?
             .into_iter()
-            .map(|message| TxOut {
-                value: Amount::from_sat(0),
-                script_pubkey: message.into(),
-            })
+            .map(Into::into)
             .collect();
-        let transasction = Transaction {
-            output,
-            input: vec![],
-            lock_time: bitcoin::absolute::LockTime::Blocks(Height::ZERO),
-            version: Version::TWO,
+        let response = GetSidechainProposalsResponse {
+            sidechain_proposals,
         };
-        let mut psbt = vec![];
-        transasction.consensus_encode(&mut psbt).unwrap();
+        Ok(Response::new(response))
+    }
 
-        let response = GetCoinbasePsbtResponse { psbt };
+    async fn get_sidechains(
+        &self,
+        request: Request<GetSidechainsRequest>,
+    ) -> Result<Response<GetSidechainsResponse>, Status> {
+        let request = request.into_inner();
+        let sidechains = self
+            .bip300
+            .get_sidechains(request.at_height)
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let response = GetSidechainsResponse { sidechains };
         Ok(Response::new(response))
     }
+
+    async fn get_bundles(
+        &self,
+        request: Request<GetBundlesRequest>,
+    ) -> Result<Response<GetBundlesResponse>, Status> {
+        let request = request.into_inner();
+        let sidechain_number = parse_sidechain_number(request.sidechain_number)?;
+        let bundles = self
+            .bip300
+            .get_bundles(sidechain_number, request.at_height)
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let response = GetBundlesResponse { bundles };
+        Ok(Response::new(response))
+    }
+
+    async fn get_ctip(
+        &self,
+        request: Request<GetCtipRequest>,
+    ) -> Result<Response<GetCtipResponse>, Status> {
+        let request = request.into_inner();
+        let sidechain_number = parse_sidechain_number(request.sidechain_number)?;
+        let ctip = self
+            .bip300
+            .get_ctip(sidechain_number, request.at_height)
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map(Into::into);
+        let response = GetCtipResponse { ctip };
+        Ok(Response::new(response))
+    }
+}
+
+fn parse_sidechain_number(sidechain_number: u32) -> Result<u8, Status> {
+    u8::try_from(sidechain_number).map_err(|_| {
+        Status::invalid_argument(format!("invalid sidechain number {sidechain_number}"))
+    })
+}
+
+impl From<(Hash256, SidechainProposal)> for bip300::SidechainProposal {
+    fn from((data_hash, sidechain_proposal): (Hash256, SidechainProposal)) -> Self {
+        Self {
+            sidechain_number: sidechain_proposal.sidechain_number as u32,
+            data: sidechain_proposal.data,
+            data_hash: data_hash.to_vec(),
+            vote_count: sidechain_proposal.vote_count as u32,
+            proposal_height: sidechain_proposal.proposal_height,
+        }
+    }
+}
+
+impl From<Sidechain> for bip300::Sidechain {
+    fn from(sidechain: Sidechain) -> Self {
+        Self {
+            sidechain_number: sidechain.sidechain_number as u32,
+            data: sidechain.data,
+            vote_count: sidechain.vote_count as u32,
+            proposal_height: sidechain.proposal_height,
+            activation_height: sidechain.activation_height,
+        }
+    }
+}
+
+impl From<Bundle> for bip300::Bundle {
+    fn from(bundle: Bundle) -> Self {
+        Self {
+            bundle_txid: bundle.bundle_txid.to_vec(),
+            vote_count: bundle.vote_count as u32,
+        }
+    }
+}
+
+impl From<Ctip> for bip300::Ctip {
+    fn from(ctip: Ctip) -> Self {
+        Self {
+            txid: ctip.outpoint.txid.to_byte_array().to_vec(),
+            vout: ctip.outpoint.vout,
+            value: ctip.value,
+        }
+    }
+}
+
+impl From<ConsensusParams> for bip300::ConsensusParams {
+    fn from(consensus_params: ConsensusParams) -> Self {
+        Self {
+            used_max_age: consensus_params.used_max_age,
+            used_threshold: consensus_params.used_threshold as u32,
+            unused_max_age: consensus_params.unused_max_age,
+            unused_threshold: consensus_params.unused_threshold as u32,
+            bundle_threshold: consensus_params.bundle_threshold as u32,
+        }
+    }
+}
+
+impl From<(u32, Event)> for bip300::Event {
+    fn from((height, event): (u32, Event)) -> Self {
+        use bip300::event;
+        let event = match event {
+            Event::SidechainProposalCreated {
+                sidechain_number,
+                data_hash,
+            } => event::Event::SidechainProposalCreated(bip300::SidechainProposalCreated {
+                sidechain_number: sidechain_number as u32,
+                data_hash: data_hash.to_vec(),
+            }),
+            Event::SidechainProposalVoted {
+                sidechain_number,
+                data_hash,
+                vote_count,
+            } => event::Event::SidechainProposalVoted(bip300::SidechainProposalVoted {
+                sidechain_number: sidechain_number as u32,
+                data_hash: data_hash.to_vec(),
+                vote_count: vote_count as u32,
+            }),
+            Event::SidechainActivated {
+                sidechain_number,
+                data_hash,
+            } => event::Event::SidechainActivated(bip300::SidechainActivated {
+                sidechain_number: sidechain_number as u32,
+                data_hash: data_hash.to_vec(),
+            }),
+            Event::SidechainProposalExpired {
+                sidechain_number,
+                data_hash,
+            } => event::Event::SidechainProposalExpired(bip300::SidechainProposalExpired {
+                sidechain_number: sidechain_number as u32,
+                data_hash: data_hash.to_vec(),
+            }),
+            Event::BundleProposed {
+                sidechain_number,
+                bundle_txid,
+            } => event::Event::BundleProposed(bip300::BundleProposed {
+                sidechain_number: sidechain_number as u32,
+                bundle_txid: bundle_txid.to_vec(),
+            }),
+            Event::BundleVoted {
+                sidechain_number,
+                bundle_txid,
+                vote_count,
+            } => event::Event::BundleVoted(bip300::BundleVoted {
+                sidechain_number: sidechain_number as u32,
+                bundle_txid: bundle_txid.to_vec(),
+                vote_count: vote_count as u32,
+            }),
+            Event::BundleApproved {
+                sidechain_number,
+                bundle_txid,
+            } => event::Event::BundleApproved(bip300::BundleApproved {
+                sidechain_number: sidechain_number as u32,
+                bundle_txid: bundle_txid.to_vec(),
+            }),
+            Event::Deposit {
+                sidechain_number,
+                outpoint,
+                value,
+                total_value,
+            } => event::Event::Deposit(bip300::Deposit {
+                sidechain_number: sidechain_number as u32,
+                txid: outpoint.txid.to_byte_array().to_vec(),
+                vout: outpoint.vout,
+                value,
+                total_value,
+            }),
+            Event::Withdrawal {
+                sidechain_number,
+                bundle_txid,
+                value,
+                total_value,
+            } => event::Event::Withdrawal(bip300::Withdrawal {
+                sidechain_number: sidechain_number as u32,
+                bundle_txid: bundle_txid.to_vec(),
+                value,
+                total_value,
+            }),
+            Event::BlockConnected { block_hash } => {
+                event::Event::BlockConnected(bip300::BlockConnected {
+                    block_hash: block_hash.to_vec(),
+                })
+            }
+            Event::BlockDisconnected { block_hash } => {
+                event::Event::BlockDisconnected(bip300::BlockDisconnected {
+                    block_hash: block_hash.to_vec(),
+                })
+            }
+        };
+        Self {
+            height,
+            event: Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::serialize;
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+
+    use super::*;
+    use crate::auth::Scope;
+    use crate::test_utils::{TestChain, START_HEIGHT};
+
+    /// The service, and the sender shutting it down.
+    fn service(chain: &TestChain) -> (ValidatorService, watch::Sender<bool>) {
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let service = ValidatorService::new(
+            chain.bip300.clone(),
+            VotePolicy::default(),
+            DEFAULT_CONNECT_BLOCKS_BATCH_SIZE,
+            shutdown_receiver,
+        );
+        (service, shutdown)
+    }
+
+    fn write_request<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Scope::Write);
+        request
+    }
+
+    #[tokio::test]
+    async fn connect_block_reports_invalid_blocks() -> Result<()> {
+        let chain = TestChain::with_sidechains(&[])?;
+        let (service, _shutdown) = service(&chain);
+        let block = serialize(&chain.next_block(vec![], vec![]));
+
+        let request = ConnectBlockRequest {
+            height: START_HEIGHT,
+            block: vec![1, 2, 3],
+        };
+        let status = service
+            .connect_block(write_request(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let request = ConnectBlockRequest {
+            height: START_HEIGHT + 1,
+            block: block.clone(),
+        };
+        let status = service
+            .connect_block(write_request(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let mut empty_block = chain.next_block(vec![], vec![]);
+        empty_block.txdata.clear();
+        let request = ConnectBlockRequest {
+            height: START_HEIGHT,
+            block: serialize(&empty_block),
+        };
+        let status = service
+            .connect_block(write_request(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let request = ConnectBlockRequest {
+            height: START_HEIGHT,
+            block,
+        };
+        service.connect_block(write_request(request)).await.unwrap();
+        assert_eq!(
+            chain.bip300.get_chain_tip()?.map(|(height, _)| height),
+            Some(START_HEIGHT)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_below_from_height_are_skipped() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[])?;
+        chain.connect(vec![], vec![])?;
+        chain.connect(vec![], vec![])?;
+        let (service, _shutdown) = service(&chain);
+        let request = Request::new(SubscribeEventsRequest {
+            from_height: Some(START_HEIGHT + 1),
+        });
+        let mut events = service
+            .subscribe_events(request)
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Reorg both blocks.
+        chain.disconnect()?;
+        chain.disconnect()?;
+        chain.connect(vec![], vec![])?;
+        chain.connect(vec![], vec![])?;
+        let mut received = vec![];
+        for _ in 0..3 {
+            let event = events.next().await.unwrap().unwrap();
+            let disconnected = matches!(
+                event.event,
+                Some(bip300::event::Event::BlockDisconnected(_))
+            );
+            received.push((event.height, disconnected));
+        }
+        assert_eq!(
+            received,
+            [
+                (START_HEIGHT + 1, false),
+                (START_HEIGHT + 1, true),
+                (START_HEIGHT + 1, false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn event_streams_end_on_shutdown() -> Result<()> {
+        let mut chain = TestChain::with_sidechains(&[])?;
+        chain.connect(vec![], vec![])?;
+        let (service, shutdown) = service(&chain);
+        let request = Request::new(SubscribeEventsRequest { from_height: None });
+        let mut events = service
+            .subscribe_events(request)
+            .await
+            .unwrap()
+            .into_inner();
+        // The stored BlockConnected event is replayed.
+        assert!(events.next().await.unwrap().is_ok());
+
+        shutdown.send_replace(true);
+        let status = events.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(events.next().await.is_none());
+        Ok(())
+    }
 }
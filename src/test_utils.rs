@@ -0,0 +1,198 @@
+//! Chains of blocks connected to a throwaway database, for tests.
+
+use std::sync::Arc;
+
+use bip300_messages::CoinbaseMessage;
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version as BlockVersion};
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Amount, Block, BlockHash, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+use miette::{IntoDiagnostic, Result};
+use tempfile::TempDir;
+
+use crate::bip300::{drivechain_script, Bip300};
+use crate::types::{ConsensusParams, Ctip, Hash256, InitialState, Sidechain, StartBlock};
+
+/// Height of the first block of every test chain, the state is seeded just below it.
+pub const START_HEIGHT: u32 = 1;
+
+pub struct TestChain {
+    pub bip300: Arc<Bip300>,
+    /// Blocks connected so far, the first one at `START_HEIGHT`.
+    pub blocks: Vec<Block>,
+    /// Holds the database, which is removed when the chain is dropped.
+    pub dir: TempDir,
+}
+
+impl TestChain {
+    pub fn new(initial_state: InitialState) -> Result<Self> {
+        Self::with_consensus_params(initial_state, ConsensusParams::default())
+    }
+
+    pub fn with_consensus_params(
+        initial_state: InitialState,
+        consensus_params: ConsensusParams,
+    ) -> Result<Self> {
+        let dir = tempfile::tempdir().into_diagnostic()?;
+        let bip300 = Bip300::new(
+            &dir.path().join("bip300.redb"),
+            Network::Regtest,
+            consensus_params,
+            StartBlock {
+                height: START_HEIGHT,
+                prev_block_hash: [0; 32],
+            },
+            None,
+        )?;
+        bip300.seed_state(initial_state)?;
+        Ok(Self {
+            bip300: Arc::new(bip300),
+            blocks: vec![],
+            dir,
+        })
+    }
+
+    /// A chain whose state starts with active sidechains numbered `sidechain_numbers`.
+    pub fn with_sidechains(sidechain_numbers: &[u8]) -> Result<Self> {
+        Self::new(InitialState {
+            sidechains: sidechain_numbers.iter().copied().map(sidechain).collect(),
+            ..InitialState::default()
+        })
+    }
+
+    /// Height the next block is connected at.
+    pub fn next_height(&self) -> u32 {
+        START_HEIGHT + self.blocks.len() as u32
+    }
+
+    /// Builds the next block, with a coinbase output per message.
+    pub fn next_block(
+        &self,
+        messages: Vec<CoinbaseMessage>,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        block(self.blocks.last(), messages, transactions)
+    }
+
+    /// Builds and connects the next block.
+    pub fn connect(
+        &mut self,
+        messages: Vec<CoinbaseMessage>,
+        transactions: Vec<Transaction>,
+    ) -> Result<Block> {
+        let block = self.next_block(messages, transactions);
+        connect_block(&self.bip300, &block, self.next_height())?;
+        self.blocks.push(block.clone());
+        Ok(block)
+    }
+
+    /// Disconnects the tip.
+    pub fn disconnect(&mut self) -> Result<Block> {
+        let block = self.blocks.pop().expect("no block to disconnect");
+        self.bip300.disconnect_block(&block)?;
+        Ok(block)
+    }
+}
+
+/// Connects `block` at `height`, failing if it is rejected.
+pub fn connect_block(bip300: &Bip300, block: &Block, height: u32) -> Result<()> {
+    match bip300.connect_blocks(&[(height, block.clone())])?.failure {
+        Some((_, err)) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Builds the block after `prev`, or the block at `START_HEIGHT` without it, with a coinbase output
+/// per message.
+pub fn block(
+    prev: Option<&Block>,
+    messages: Vec<CoinbaseMessage>,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let (height, prev_blockhash) = match prev {
+        // Test blocks are timestamped with their height.
+        Some(prev) => (prev.header.time + 1, prev.block_hash()),
+        None => (START_HEIGHT, BlockHash::all_zeros()),
+    };
+    let coinbase = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            // The height makes every coinbase txid unique, as in BIP34.
+            script_sig: ScriptBuf::builder().push_int(height as i64).into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: messages
+            .into_iter()
+            .map(|message| TxOut {
+                value: Amount::ZERO,
+                script_pubkey: message.into(),
+            })
+            .collect(),
+    };
+    let mut block = Block {
+        header: Header {
+            version: BlockVersion::TWO,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: height,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: [coinbase].into_iter().chain(transactions).collect(),
+    };
+    // Commit to the transactions so that blocks differing only in them have different hashes.
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    block
+}
+
+pub fn sidechain(sidechain_number: u8) -> Sidechain {
+    Sidechain {
+        sidechain_number,
+        data: vec![sidechain_number],
+        vote_count: 0,
+        proposal_height: 0,
+        activation_height: 0,
+    }
+}
+
+/// A made up txid or data hash.
+pub fn hash(n: u8) -> Hash256 {
+    [n; 32]
+}
+
+/// A ctip at the first output of a made up transaction.
+pub fn ctip(n: u8, value: u64) -> Ctip {
+    Ctip {
+        outpoint: OutPoint {
+            txid: Txid::from_byte_array(hash(n)),
+            vout: 0,
+        },
+        value,
+    }
+}
+
+/// A transaction spending `ctip` into a new ctip of `value`, a deposit if it is larger and a
+/// withdrawal otherwise.
+pub fn spend_ctip(sidechain_number: u8, ctip: &Ctip, value: u64) -> Transaction {
+    Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: ctip.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: drivechain_script(sidechain_number),
+        }],
+    }
+}
@@ -3,19 +3,24 @@ use bitcoin::{OutPoint, Txid};
 use byteorder::{BigEndian, ByteOrder};
 use redb::{RedbValue, TypeName};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::mem::size_of;
 
 pub type Hash256 = [u8; 32];
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Ctip {
     pub outpoint: OutPoint,
     pub value: u64,
 }
 
+/// Encoded length of a `Ctip`: txid, vout and value. `size_of::<Ctip>()` includes padding and
+/// can't be used.
+const CTIP_LEN: usize = size_of::<Hash256>() + size_of::<u32>() + size_of::<u64>();
+
 impl RedbValue for Ctip {
     type SelfType<'a> = Ctip;
-    type AsBytes<'a> = [u8; size_of::<Ctip>()];
+    type AsBytes<'a> = [u8; CTIP_LEN];
 
     fn type_name() -> TypeName {
         TypeName::new("Ctip")
@@ -26,18 +31,21 @@ impl RedbValue for Ctip {
         Self: 'a,
         Self: 'b,
     {
-        [
-            value.outpoint.txid.to_byte_array().to_vec(),
-            value.outpoint.vout.to_be_bytes().to_vec(),
-            value.value.to_be_bytes().to_vec(),
-        ]
-        .concat()
-        .try_into()
-        .unwrap()
+        let mut data = [0; CTIP_LEN];
+        data[..size_of::<Hash256>()].copy_from_slice(&value.outpoint.txid.to_byte_array());
+        BigEndian::write_u32(
+            &mut data[size_of::<Hash256>()..size_of::<Hash256>() + size_of::<u32>()],
+            value.outpoint.vout,
+        );
+        BigEndian::write_u64(
+            &mut data[size_of::<Hash256>() + size_of::<u32>()..],
+            value.value,
+        );
+        data
     }
 
     fn fixed_width() -> Option<usize> {
-        Some(size_of::<Ctip>())
+        Some(CTIP_LEN)
     }
 
     fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
@@ -65,14 +73,14 @@ pub struct Deposit {
 
 impl RedbValue for Deposit {
     type SelfType<'a> = Deposit;
-    type AsBytes<'a> = [u8; size_of::<Hash256>() + size_of::<u64>() + size_of::<u64>()];
+    type AsBytes<'a> = [u8; size_of::<Hash256>() + 2 * size_of::<u64>()];
 
     fn type_name() -> TypeName {
         TypeName::new("Deposit")
     }
 
     fn fixed_width() -> Option<usize> {
-        Some(size_of::<Hash256>() + size_of::<u64>())
+        Some(size_of::<Hash256>() + 2 * size_of::<u64>())
     }
 
     fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
@@ -96,7 +104,7 @@ impl RedbValue for Deposit {
         Self: 'a,
         Self: 'b,
     {
-        let mut data = [0; size_of::<Hash256>() + size_of::<u64>() + size_of::<u64>()];
+        let mut data = [0; size_of::<Hash256>() + 2 * size_of::<u64>()];
         data[0..size_of::<Hash256>()].copy_from_slice(&value.address);
         BigEndian::write_u64(
             &mut data[size_of::<Hash256>()..size_of::<Hash256>() + size_of::<u64>()],
@@ -184,7 +192,7 @@ impl RedbValue for SidechainProposal {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Bundle {
     pub bundle_txid: Hash256,
     pub vote_count: u16,
@@ -192,12 +200,110 @@ pub struct Bundle {
 
 impl RedbValue for Bundle {
     type SelfType<'a> = Bundle;
-    type AsBytes<'a> = Vec<u8>;
+    type AsBytes<'a> = [u8; size_of::<Hash256>() + size_of::<u16>()];
 
     fn type_name() -> TypeName {
         TypeName::new("Bundle")
     }
 
+    fn fixed_width() -> Option<usize> {
+        Some(size_of::<Hash256>() + size_of::<u16>())
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        let mut data = [0; size_of::<Hash256>() + size_of::<u16>()];
+        data[..size_of::<Hash256>()].copy_from_slice(&value.bundle_txid);
+        BigEndian::write_u16(&mut data[size_of::<Hash256>()..], value.vote_count);
+        data
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        let mut bundle_txid = [0; size_of::<Hash256>()];
+        bundle_txid.copy_from_slice(&data[..size_of::<Hash256>()]);
+        let vote_count = BigEndian::read_u16(&data[size_of::<Hash256>()..]);
+        Bundle {
+            bundle_txid,
+            vote_count,
+        }
+    }
+}
+
+/// What an M4 does for one sidechain. Upvotes refer to the bundle by txid rather than index, so
+/// they still mean the same bundle after earlier bundles are paid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Vote {
+    Abstain,
+    /// Takes a vote away from every bundle of the sidechain.
+    Alarm,
+    Upvote {
+        bundle_txid: Hash256,
+    },
+}
+
+impl RedbValue for Vote {
+    type SelfType<'a> = Vote;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn type_name() -> TypeName {
+        TypeName::new("Vote")
+    }
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusParams {
+    pub used_max_age: u32,
+    pub used_threshold: u16,
+    pub unused_max_age: u32,
+    pub unused_threshold: u16,
+    pub bundle_threshold: u16,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            used_max_age: 26_300,
+            used_threshold: 13_150,
+            unused_max_age: 2016,
+            unused_threshold: 2016 - 201,
+            bundle_threshold: 13_150,
+        }
+    }
+}
+
+impl RedbValue for ConsensusParams {
+    type SelfType<'a> = ConsensusParams;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn type_name() -> TypeName {
+        TypeName::new("ConsensusParams")
+    }
+
     fn fixed_width() -> Option<usize> {
         None
     }
@@ -217,3 +323,169 @@ impl RedbValue for Bundle {
         bincode::deserialize(data).unwrap()
     }
 }
+
+/// The first block to connect to an empty database. Earlier blocks are skipped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartBlock {
+    pub height: u32,
+    pub prev_block_hash: Hash256,
+}
+
+/// State to start from instead of an empty one, for test networks.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InitialState {
+    #[serde(default)]
+    pub sidechains: Vec<Sidechain>,
+    #[serde(default)]
+    pub ctips: BTreeMap<u8, Ctip>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub block_hash: Hash256,
+    pub prev_block_hash: Hash256,
+    /// Hash of the full BIP300 state after this block was connected.
+    pub state_commitment: Hash256,
+}
+
+impl RedbValue for BlockInfo {
+    type SelfType<'a> = BlockInfo;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn type_name() -> TypeName {
+        TypeName::new("BlockInfo")
+    }
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    SidechainProposalCreated {
+        sidechain_number: u8,
+        data_hash: Hash256,
+    },
+    SidechainProposalVoted {
+        sidechain_number: u8,
+        data_hash: Hash256,
+        vote_count: u16,
+    },
+    SidechainActivated {
+        sidechain_number: u8,
+        data_hash: Hash256,
+    },
+    SidechainProposalExpired {
+        sidechain_number: u8,
+        data_hash: Hash256,
+    },
+    BundleProposed {
+        sidechain_number: u8,
+        bundle_txid: Hash256,
+    },
+    BundleVoted {
+        sidechain_number: u8,
+        bundle_txid: Hash256,
+        vote_count: u16,
+    },
+    BundleApproved {
+        sidechain_number: u8,
+        bundle_txid: Hash256,
+    },
+    Deposit {
+        sidechain_number: u8,
+        outpoint: OutPoint,
+        value: u64,
+        total_value: u64,
+    },
+    Withdrawal {
+        sidechain_number: u8,
+        bundle_txid: Hash256,
+        value: u64,
+        total_value: u64,
+    },
+    BlockConnected {
+        block_hash: Hash256,
+    },
+    BlockDisconnected {
+        block_hash: Hash256,
+    },
+}
+
+impl RedbValue for Event {
+    type SelfType<'a> = Event;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn type_name() -> TypeName {
+        TypeName::new("Event")
+    }
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        bincode::deserialize(data).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctip_round_trips() {
+        let ctip = Ctip {
+            outpoint: OutPoint {
+                txid: Txid::from_byte_array([1; 32]),
+                vout: 0x0203_0405,
+            },
+            value: 0x0607_0809_0a0b_0c0d,
+        };
+        let data = Ctip::as_bytes(&ctip);
+        assert_eq!(Some(data.len()), Ctip::fixed_width());
+        assert_eq!(Ctip::from_bytes(&data), ctip);
+    }
+
+    #[test]
+    fn deposit_round_trips() {
+        let deposit = Deposit {
+            address: [1; 32],
+            value: 0x0203_0405_0607_0809,
+            total_value: 0x0a0b_0c0d_0e0f_1011,
+        };
+        let data = Deposit::as_bytes(&deposit);
+        assert_eq!(Some(data.len()), Deposit::fixed_width());
+        let decoded = Deposit::from_bytes(&data);
+        assert_eq!(decoded.address, deposit.address);
+        assert_eq!(decoded.value, deposit.value);
+        assert_eq!(decoded.total_value, deposit.total_value);
+    }
+}
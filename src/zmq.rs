@@ -0,0 +1,106 @@
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use miette::{IntoDiagnostic, Result};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+/// Subscribes to bitcoind's `zmqpubhashblock` notifications.
+///
+/// The hash of every new block is forwarded to the follower, so that new blocks are processed as
+/// soon as bitcoind accepts them.
+pub struct ZmqListener {
+    address: String,
+    new_blocks: mpsc::Sender<BlockHash>,
+}
+
+impl ZmqListener {
+    pub fn new(address: String, new_blocks: mpsc::Sender<BlockHash>) -> Self {
+        Self {
+            address,
+            new_blocks,
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut socket = SubSocket::new();
+        socket.connect(&self.address).await.into_diagnostic()?;
+        socket.subscribe("hashblock").await.into_diagnostic()?;
+        info!(address = %self.address, "listening for block notifications");
+        loop {
+            let message = socket.recv().await.into_diagnostic()?;
+            let (Some(topic), Some(body)) = (message.get(0), message.get(1)) else {
+                continue;
+            };
+            if topic.as_ref() != b"hashblock" {
+                continue;
+            }
+            // The hash is sent in the byte order it is displayed in.
+            let Ok(mut block_hash) = <[u8; 32]>::try_from(body.as_ref()) else {
+                warn!(len = body.len(), "failed to decode block notification");
+                continue;
+            };
+            block_hash.reverse();
+            let block_hash = BlockHash::from_byte_array(block_hash);
+            if self.new_blocks.send(block_hash).await.is_err() {
+                // The follower stopped, nothing is left to notify.
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use zeromq::{PubSocket, SocketSend, ZmqMessage};
+
+    use super::*;
+
+    fn notification(topic: &str, body: [u8; 32]) -> ZmqMessage {
+        let mut message = ZmqMessage::from(topic);
+        message.push_back(body.to_vec().into());
+        // bitcoind numbers the notifications of each topic.
+        message.push_back(0u32.to_le_bytes().to_vec().into());
+        message
+    }
+
+    #[tokio::test]
+    async fn forwards_announced_block_hashes() -> Result<()> {
+        let mut publisher = PubSocket::new();
+        let endpoint = publisher
+            .bind("tcp://127.0.0.1:0")
+            .await
+            .into_diagnostic()?;
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(ZmqListener::new(endpoint.to_string(), sender).run());
+
+        let mut displayed_hash = [0; 32];
+        displayed_hash[0] = 1;
+        let expected = format!("01{}", "00".repeat(31));
+        // Notifications are dropped until the subscription reaches the publisher.
+        let block_hash = loop {
+            publisher
+                .send(notification("rawblock", [2; 32]))
+                .await
+                .into_diagnostic()?;
+            publisher
+                .send(notification("hashblock", displayed_hash))
+                .await
+                .into_diagnostic()?;
+            let received = tokio::time::timeout(Duration::from_millis(50), receiver.recv()).await;
+            if let Ok(block_hash) = received {
+                break block_hash.unwrap();
+            }
+        };
+        assert_eq!(block_hash.to_string(), expected);
+        // Only hashblock is subscribed to.
+        while let Ok(block_hash) =
+            tokio::time::timeout(Duration::from_millis(50), receiver.recv()).await
+        {
+            assert_eq!(block_hash.unwrap().to_string(), expected);
+        }
+        Ok(())
+    }
+}